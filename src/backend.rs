@@ -0,0 +1,472 @@
+use anyhow::{bail, format_err, Context, Result};
+use pos58_usb::POS58USB;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::codepage::CodePage;
+use crate::emulator::EmulatorBackend;
+use crate::escpos::{DLE, EOT, STATUS_OFFLINE_CAUSE, STATUS_PRINTER, STATUS_ROLL_PAPER};
+
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(5);
+const USB_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to wait for each status byte
+const STATUS_TIMEOUT_MS: i32 = 500;
+
+/// Printer health, as far as the backend can tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrinterStatus {
+    /// The backend or printer has no way of telling us
+    Unknown,
+    /// The printer is connected and accepting data
+    Ready,
+    PaperOut,
+    CoverOpen,
+    /// Not connected, or offline for some other reason
+    Offline,
+}
+
+impl PrinterStatus {
+    /// Whether it's worth sending jobs to the printer
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, PrinterStatus::Unknown | PrinterStatus::Ready)
+    }
+}
+
+impl fmt::Display for PrinterStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PrinterStatus::Unknown | PrinterStatus::Ready => "The printer is back online!",
+            PrinterStatus::PaperOut => "The printer is out of paper! Print jobs will be held until it's refilled.",
+            PrinterStatus::CoverOpen => "The printer's cover is open! Print jobs will be held until it's closed.",
+            PrinterStatus::Offline => "The printer is offline! Print jobs will be held until it's back.",
+        })
+    }
+}
+
+/// Something ESC/POS bytes can be sent to. Raw bytes go through `Write`.
+pub trait PrinterBackend: Write {
+    /// Human readable description, for logging
+    fn describe(&self) -> String;
+
+    /// Query the state of the printer
+    fn status(&mut self) -> Result<PrinterStatus>;
+
+    /// Whether the printer draws barcodes itself (GS k). Otherwise they are sent as images.
+    fn native_barcodes(&self) -> bool {
+        false
+    }
+}
+
+/// Which backend to use, as selected on the command line
+#[derive(Debug, Clone)]
+pub enum BackendConfig {
+    /// POS58 printer driven directly over libusb
+    Pos58,
+    /// Raw printer device file, e.g. /dev/usb/lp0
+    Device(PathBuf),
+    /// Network printer speaking raw ESC/POS, usually on port 9100
+    Network(String),
+    /// Plain file or pipe. `-` means stdout.
+    File(PathBuf),
+    /// No printer at all, render the paper roll to a PNG instead
+    Emulator(PathBuf),
+}
+
+impl FromStr for BackendConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, arg) = match s.find(':') {
+            Some(idx) => (&s[..idx], Some(&s[idx + 1..])),
+            None => (s, None),
+        };
+
+        match (kind, arg) {
+            ("pos58", None) => Ok(Self::Pos58),
+            ("lp", Some(path)) => Ok(Self::Device(path.into())),
+            ("tcp", Some(addr)) if addr.contains(':') => Ok(Self::Network(addr.into())),
+            ("tcp", Some(host)) => Ok(Self::Network(format!("{}:9100", host))),
+            ("file", Some(path)) => Ok(Self::File(path.into())),
+            ("emulator", Some(path)) => Ok(Self::Emulator(path.into())),
+            _ => Err(format_err!(
+                "Unknown printer backend \"{}\", expected one of pos58, lp:<device>, tcp:<host[:port]>, file:<path>, emulator:<png>",
+                s
+            )),
+        }
+    }
+}
+
+/// The printer to use, and settings which depend on the printer, e.g.
+/// `tcp:10.0.0.5?code_pages=cp858,cp1252`
+#[derive(Debug, Clone)]
+pub struct PrinterConfig {
+    pub backend: BackendConfig,
+    /// Code pages the printer's own font supports, in order of preference. Characters outside
+    /// them are drawn instead.
+    pub code_pages: Vec<CodePage>,
+}
+
+impl FromStr for PrinterConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (backend, settings) = match s.rfind('?') {
+            Some(idx) => (&s[..idx], &s[idx + 1..]),
+            None => (s, ""),
+        };
+        let backend: BackendConfig = backend.parse()?;
+
+        // The emulator only has ASCII glyphs, so by default it gets everything else drawn
+        let mut code_pages = match backend {
+            BackendConfig::Emulator(_) => Vec::new(),
+            _ => vec![CodePage::Cp437],
+        };
+        for setting in settings.split('&').filter(|s| !s.is_empty()) {
+            match setting.split_once('=') {
+                Some(("code_pages", pages)) => {
+                    code_pages = pages
+                        .split(',')
+                        .filter(|p| !p.is_empty())
+                        .map(str::parse)
+                        .collect::<Result<_>>()?;
+                }
+                _ => bail!(
+                    "Unknown printer setting \"{}\", expected code_pages=<cp437,cp850,...>",
+                    setting
+                ),
+            }
+        }
+
+        Ok(Self {
+            backend,
+            code_pages,
+        })
+    }
+}
+
+impl BackendConfig {
+    /// Open the configured backend. The USB backend borrows `usb_context`, which is created on demand.
+    pub fn open<'a>(
+        &self,
+        usb_context: &'a mut Option<libusb::Context>,
+    ) -> Result<Box<dyn PrinterBackend + 'a>> {
+        Ok(match self {
+            Self::Pos58 => {
+                if usb_context.is_none() {
+                    *usb_context =
+                        Some(libusb::Context::new().context("Failed to create LibUSB context.")?);
+                }
+                let context = usb_context.as_mut().unwrap();
+                let device =
+                    POS58USB::new(context, USB_TIMEOUT).context("Failed to connect to printer")?;
+                Box::new(Pos58Backend(device))
+            }
+            Self::Device(path) => {
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open printer device {:?}", path))?;
+                Box::new(DeviceBackend {
+                    path: path.clone(),
+                    file,
+                })
+            }
+            Self::Network(addr) => {
+                let sock_addr = addr
+                    .to_socket_addrs()
+                    .with_context(|| format!("Failed to resolve {}", addr))?
+                    .next()
+                    .with_context(|| format!("No address for {}", addr))?;
+                let stream = TcpStream::connect_timeout(&sock_addr, NETWORK_TIMEOUT)
+                    .with_context(|| format!("Failed to connect to {}", addr))?;
+                stream.set_write_timeout(Some(NETWORK_TIMEOUT))?;
+                // Only status bytes are read, and the printer thread waits on each of them
+                stream.set_read_timeout(Some(Duration::from_millis(STATUS_TIMEOUT_MS as u64)))?;
+                Box::new(NetworkBackend {
+                    addr: addr.clone(),
+                    stream,
+                })
+            }
+            Self::File(path) if path.as_os_str() == "-" => Box::new(FileBackend {
+                path: path.clone(),
+                sink: Box::new(io::stdout()),
+            }),
+            Self::File(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open {:?}", path))?;
+                Box::new(FileBackend {
+                    path: path.clone(),
+                    sink: Box::new(file),
+                })
+            }
+            Self::Emulator(path) => Box::new(EmulatorBackend::new(path.clone())),
+        })
+    }
+}
+
+/// POS58 over libusb. It can't report the printer's status, so jobs are sent regardless and
+/// whatever is printed while it's out of paper is lost. Use the `lp:` backend to avoid that.
+struct Pos58Backend<'a>(POS58USB<'a>);
+
+impl Write for Pos58Backend<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl PrinterBackend for Pos58Backend<'_> {
+    fn describe(&self) -> String {
+        "POS58 (libusb)".into()
+    }
+
+    fn status(&mut self) -> Result<PrinterStatus> {
+        // pos58_usb only exposes the printer's OUT endpoint, so we can't hear back from it
+        Ok(PrinterStatus::Unknown)
+    }
+}
+
+/// Kernel printer device, e.g. /dev/usb/lp0 from the usblp module
+struct DeviceBackend {
+    path: PathBuf,
+    file: File,
+}
+
+impl Write for DeviceBackend {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl StatusPort for DeviceBackend {
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        // Not every printer answers, so don't block on the read
+        let mut fd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fd, 1, STATUS_TIMEOUT_MS) } {
+            n if n < 0 => Err(io::Error::last_os_error().into()),
+            0 => Ok(None),
+            _ => {
+                let mut buf = [0];
+                match self.file.read(&mut buf)? {
+                    0 => Ok(None),
+                    _ => Ok(Some(buf[0])),
+                }
+            }
+        }
+    }
+}
+
+impl PrinterBackend for DeviceBackend {
+    fn describe(&self) -> String {
+        format!("device {}", self.path.display())
+    }
+
+    fn status(&mut self) -> Result<PrinterStatus> {
+        query_status(self)
+    }
+
+    fn native_barcodes(&self) -> bool {
+        true
+    }
+}
+
+/// Network printer, raw TCP
+struct NetworkBackend {
+    addr: String,
+    stream: TcpStream,
+}
+
+impl Write for NetworkBackend {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl StatusPort for NetworkBackend {
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut buf = [0];
+        match self.stream.read(&mut buf) {
+            Ok(0) => bail!("Connection to {} closed", self.addr),
+            Ok(_) => Ok(Some(buf[0])),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl PrinterBackend for NetworkBackend {
+    fn describe(&self) -> String {
+        format!("network printer at {}", self.addr)
+    }
+
+    fn status(&mut self) -> Result<PrinterStatus> {
+        if let Some(e) = self.stream.take_error()? {
+            bail!("Connection to {} failed: {}", self.addr, e);
+        }
+        query_status(self)
+    }
+
+    fn native_barcodes(&self) -> bool {
+        true
+    }
+}
+
+/// File or pipe sink, handy for capturing the raw byte stream
+struct FileBackend {
+    path: PathBuf,
+    sink: Box<dyn Write>,
+}
+
+impl Write for FileBackend {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sink.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+impl PrinterBackend for FileBackend {
+    fn describe(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+
+    fn status(&mut self) -> Result<PrinterStatus> {
+        Ok(PrinterStatus::Unknown)
+    }
+
+    fn native_barcodes(&self) -> bool {
+        true
+    }
+}
+
+/// Backends which can hear back from the printer
+trait StatusPort: Write {
+    /// Read a byte, or None if the printer didn't say anything in time
+    fn read_byte(&mut self) -> Result<Option<u8>>;
+}
+
+/// Ask the printer how it's doing with DLE EOT real-time status requests
+fn query_status(port: &mut impl StatusPort) -> Result<PrinterStatus> {
+    let mut responses = [0; 3];
+    for (response, &n) in responses
+        .iter_mut()
+        .zip(&[STATUS_PRINTER, STATUS_OFFLINE_CAUSE, STATUS_ROLL_PAPER])
+    {
+        port.write_all(&[DLE, EOT, n])?;
+        port.flush()?;
+        *response = match port.read_byte()? {
+            Some(byte) => byte,
+            None => return Ok(PrinterStatus::Unknown),
+        };
+    }
+    let [printer, offline_cause, roll_paper] = responses;
+    Ok(parse_status(printer, offline_cause, roll_paper))
+}
+
+/// Interpret the responses to DLE EOT 1, 2 and 4
+fn parse_status(printer: u8, offline_cause: u8, roll_paper: u8) -> PrinterStatus {
+    // Status bytes always have bit 1 and 4 set, and bit 0 and 7 clear
+    let valid = |b: u8| b & 0x93 == 0x12;
+    if !(valid(printer) && valid(offline_cause) && valid(roll_paper)) {
+        return PrinterStatus::Unknown;
+    }
+
+    if roll_paper & 0x60 != 0 || offline_cause & 0x20 != 0 {
+        PrinterStatus::PaperOut
+    } else if offline_cause & 0x04 != 0 {
+        PrinterStatus::CoverOpen
+    } else if printer & 0x08 != 0 {
+        PrinterStatus::Offline
+    } else {
+        PrinterStatus::Ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_backend() {
+        assert!(matches!("pos58".parse(), Ok(BackendConfig::Pos58)));
+        assert!(matches!(
+            "lp:/dev/usb/lp0".parse(),
+            Ok(BackendConfig::Device(p)) if p == PathBuf::from("/dev/usb/lp0")
+        ));
+        assert!(matches!(
+            "tcp:10.0.0.5".parse(),
+            Ok(BackendConfig::Network(a)) if a == "10.0.0.5:9100"
+        ));
+        assert!(matches!(
+            "tcp:printer.local:9101".parse(),
+            Ok(BackendConfig::Network(a)) if a == "printer.local:9101"
+        ));
+        assert!(matches!("file:-".parse(), Ok(BackendConfig::File(_))));
+        assert!(matches!(
+            "emulator:roll.png".parse(),
+            Ok(BackendConfig::Emulator(p)) if p == PathBuf::from("roll.png")
+        ));
+        assert!("lp".parse::<BackendConfig>().is_err());
+        assert!("serial:/dev/ttyS0".parse::<BackendConfig>().is_err());
+    }
+
+    #[test]
+    fn test_parse_printer() {
+        let printer: PrinterConfig = "tcp:10.0.0.5?code_pages=cp858,1252".parse().unwrap();
+        assert!(matches!(printer.backend, BackendConfig::Network(a) if a == "10.0.0.5:9100"));
+        assert_eq!(printer.code_pages, vec![CodePage::Cp858, CodePage::Cp1252]);
+
+        // Defaults depend on the backend
+        let printer: PrinterConfig = "pos58".parse().unwrap();
+        assert_eq!(printer.code_pages, vec![CodePage::Cp437]);
+        let printer: PrinterConfig = "emulator:roll.png".parse().unwrap();
+        assert!(printer.code_pages.is_empty());
+        let printer: PrinterConfig = "emulator:roll.png?code_pages=cp437".parse().unwrap();
+        assert_eq!(printer.code_pages, vec![CodePage::Cp437]);
+        let printer: PrinterConfig = "lp:/dev/usb/lp0?code_pages=".parse().unwrap();
+        assert!(printer.code_pages.is_empty());
+
+        assert!("pos58?code_pages=cp999".parse::<PrinterConfig>().is_err());
+        assert!("pos58?speed=fast".parse::<PrinterConfig>().is_err());
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(parse_status(0x12, 0x12, 0x12), PrinterStatus::Ready);
+        assert_eq!(parse_status(0x1A, 0x32, 0x72), PrinterStatus::PaperOut);
+        assert_eq!(parse_status(0x1A, 0x16, 0x12), PrinterStatus::CoverOpen);
+        assert_eq!(parse_status(0x1A, 0x12, 0x12), PrinterStatus::Offline);
+        // Paper near end is fine
+        assert_eq!(parse_status(0x12, 0x12, 0x1E), PrinterStatus::Ready);
+        assert_eq!(parse_status(0x00, 0x12, 0x12), PrinterStatus::Unknown);
+    }
+}
@@ -0,0 +1,350 @@
+use anyhow::{bail, ensure, format_err, Result};
+use image::{Rgb, RgbImage};
+use std::iter::once;
+use std::str::FromStr;
+
+use crate::emulator::{font_dot, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::escpos::{ESC, GS};
+use crate::printer::PRINTER_DOTS_PER_LINE;
+
+/// Height of the bars in dots
+pub const BARCODE_HEIGHT: u32 = 80;
+/// Blank modules either side of the bars
+const QUIET_ZONE: u32 = 10;
+/// Widest bar module in dots. Wider bars don't scan any better.
+const MAX_MODULE_DOTS: u32 = 3;
+/// Gap between the bars and the text below them, in dots
+const TEXT_GAP: u32 = 4;
+
+/// Supported barcode types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symbology {
+    Code128,
+    Ean13,
+    UpcA,
+}
+
+impl Symbology {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Symbology::Code128 => "code128",
+            Symbology::Ean13 => "ean13",
+            Symbology::UpcA => "upca",
+        }
+    }
+}
+
+impl FromStr for Symbology {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().replace('-', "").as_str() {
+            "code128" => Ok(Symbology::Code128),
+            "ean13" | "ean" => Ok(Symbology::Ean13),
+            "upca" | "upc" => Ok(Symbology::UpcA),
+            _ => Err(format_err!(
+                "Unknown barcode type {}, try code128, ean13 or upca",
+                s
+            )),
+        }
+    }
+}
+
+/// A validated barcode. EAN-13 and UPC-A data always includes the check digit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Barcode {
+    symbology: Symbology,
+    data: String,
+}
+
+impl Barcode {
+    /// Validate data for the given symbology. The check digit of EAN-13 and UPC-A codes is
+    /// calculated if left out, and verified otherwise.
+    pub fn new(symbology: Symbology, data: &str) -> Result<Self> {
+        let data = match symbology {
+            Symbology::Code128 => {
+                ensure!(!data.is_empty(), "Nothing to put in the barcode");
+                ensure!(
+                    data.bytes().all(|b| (0x20..0x7F).contains(&b)),
+                    "Code128 barcodes can only hold printable ASCII"
+                );
+                data.to_string()
+            }
+            Symbology::Ean13 => with_check_digit(data, 12)?,
+            Symbology::UpcA => with_check_digit(data, 11)?,
+        };
+
+        let barcode = Self { symbology, data };
+        ensure!(
+            barcode.modules().len() as u32 + 2 * QUIET_ZONE <= PRINTER_DOTS_PER_LINE,
+            "That's too long to fit on the paper"
+        );
+        Ok(barcode)
+    }
+
+    pub fn symbology(&self) -> Symbology {
+        self.symbology
+    }
+
+    /// Human readable text printed below the bars
+    pub fn data(&self) -> &str {
+        &self.data
+    }
+
+    /// Bars (true) and spaces, one per module, without quiet zones
+    fn modules(&self) -> Vec<bool> {
+        match self.symbology {
+            Symbology::Code128 => code128_modules(&self.data),
+            Symbology::Ean13 => ean13_modules(&self.data),
+            // UPC-A is EAN-13 with a leading zero
+            Symbology::UpcA => ean13_modules(&format!("0{}", self.data)),
+        }
+    }
+
+    /// Width of a module in dots
+    fn module_dots(&self) -> u32 {
+        let modules = self.modules().len() as u32 + 2 * QUIET_ZONE;
+        (PRINTER_DOTS_PER_LINE / modules).min(MAX_MODULE_DOTS)
+    }
+
+    /// Render the bars with the text below them in the printer's font, like `escpos` has the
+    /// printer do, for printers which can't draw barcodes themselves
+    pub fn image(&self) -> RgbImage {
+        let modules = self.modules();
+        let scale = self.module_dots();
+        let bars_width = (modules.len() as u32 + 2 * QUIET_ZONE) * scale;
+        let text_width = self.data.len() as u32 * GLYPH_WIDTH;
+        let width = bars_width.max(text_width);
+        let bars_x = (width - bars_width) / 2;
+        let text_x = (width - text_width) / 2;
+        let text_y = BARCODE_HEIGHT + TEXT_GAP;
+
+        RgbImage::from_fn(width, text_y + GLYPH_HEIGHT, |x, y| {
+            let black = if y < BARCODE_HEIGHT {
+                x.checked_sub(bars_x)
+                    .and_then(|x| (x / scale).checked_sub(QUIET_ZONE))
+                    .and_then(|m| modules.get(m as usize))
+                    .copied()
+                    .unwrap_or(false)
+            } else if y >= text_y && x >= text_x && x < text_x + text_width {
+                let x = x - text_x;
+                let c = self.data.as_bytes()[(x / GLYPH_WIDTH) as usize];
+                font_dot(c, x % GLYPH_WIDTH, y - text_y)
+            } else {
+                false
+            };
+            match black {
+                true => Rgb([0x00; 3]),
+                false => Rgb([0xFF; 3]),
+            }
+        })
+    }
+
+    /// Native GS k commands, centred with the text printed below
+    pub fn escpos(&self) -> Vec<u8> {
+        let (m, payload) = match self.symbology {
+            Symbology::UpcA => (65, self.data.clone().into_bytes()),
+            Symbology::Ean13 => (67, self.data.clone().into_bytes()),
+            // Always code set B, where a literal '{' has to be doubled
+            Symbology::Code128 => {
+                let data = self.data.replace('{', "{{");
+                (73, format!("{{B{}", data).into_bytes())
+            }
+        };
+
+        let mut out = Vec::new();
+        out.extend_from_slice(&[ESC, b'a', 1]); // Centre
+        out.extend_from_slice(&[GS, b'H', 2]); // Text below the bars
+        out.extend_from_slice(&[GS, b'h', BARCODE_HEIGHT as u8]);
+        out.extend_from_slice(&[GS, b'w', self.module_dots() as u8]);
+        out.extend_from_slice(&[GS, b'k', m, payload.len() as u8]);
+        out.extend(payload);
+        out
+    }
+}
+
+/// Append the check digit to `len` digits, or verify it if it's already there
+fn with_check_digit(data: &str, len: usize) -> Result<String> {
+    ensure!(
+        !data.is_empty() && data.bytes().all(|b| b.is_ascii_digit()),
+        "That kind of barcode can only hold digits"
+    );
+    let digits: Vec<u32> = data.bytes().map(|b| (b - b'0') as u32).collect();
+    match digits.len() {
+        n if n == len => Ok(format!("{}{}", data, check_digit(&digits))),
+        n if n == len + 1 => {
+            let check = check_digit(&digits[..len]);
+            ensure!(
+                digits[len] == check,
+                "Wrong check digit {}, it should be {}",
+                digits[len],
+                check
+            );
+            Ok(data.to_string())
+        }
+        _ => bail!(
+            "Expected {} digits, or {} including the check digit",
+            len,
+            len + 1
+        ),
+    }
+}
+
+/// GS1 check digit. Weights alternate 3, 1, ... starting from the rightmost digit.
+fn check_digit(digits: &[u32]) -> u32 {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| if i % 2 == 0 { d * 3 } else { *d })
+        .sum();
+    (10 - sum % 10) % 10
+}
+
+/// EAN-13 left hand digits with odd parity. Even parity codes are these inverted and reversed,
+/// and right hand digits are these inverted.
+const EAN_L: [u8; 10] = [0x0D, 0x19, 0x13, 0x3D, 0x23, 0x31, 0x2F, 0x3B, 0x37, 0x0B];
+
+/// Parity of the left hand digits, which encodes the first digit
+const EAN_PARITY: [&str; 10] = [
+    "LLLLLL", "LLGLGG", "LLGGLG", "LLGGGL", "LGLLGG", "LGGLLG", "LGGGLL", "LGLGLG", "LGLGGL",
+    "LGGLGL",
+];
+
+fn ean13_modules(data: &str) -> Vec<bool> {
+    let digits: Vec<usize> = data.bytes().map(|b| (b - b'0') as usize).collect();
+    let mut out = Vec::with_capacity(95);
+    let mut push = |bits: u8, n: u32| out.extend((0..n).rev().map(|i| bits >> i & 1 != 0));
+
+    push(0b101, 3);
+    for (&d, parity) in digits[1..7].iter().zip(EAN_PARITY[digits[0]].chars()) {
+        let code = match parity {
+            'L' => EAN_L[d],
+            _ => (!EAN_L[d] & 0x7F).reverse_bits() >> 1,
+        };
+        push(code, 7);
+    }
+    push(0b01010, 5);
+    for &d in &digits[7..] {
+        push(!EAN_L[d] & 0x7F, 7);
+    }
+    push(0b101, 3);
+    out
+}
+
+/// Code128 symbols as alternating bar and space widths, indexed by value
+const CODE128: [&str; 107] = [
+    "212222", "222122", "222221", "121223", "121322", "131222", "122213", "122312", "132212",
+    "221213", "221312", "231212", "112232", "122132", "122231", "113222", "123122", "123221",
+    "223211", "221132", "221231", "213212", "223112", "312131", "311222", "321122", "321221",
+    "312212", "322112", "322211", "212123", "212321", "232121", "111323", "131123", "131321",
+    "112313", "132113", "132311", "211313", "231113", "231311", "112133", "112331", "132131",
+    "113123", "113321", "133121", "313121", "211331", "231131", "213113", "213311", "213131",
+    "311123", "311321", "331121", "312113", "312311", "332111", "314111", "221411", "431111",
+    "111224", "111422", "121124", "121421", "141122", "141221", "112214", "112412", "122114",
+    "122411", "142112", "142211", "241211", "221114", "413111", "241112", "134111", "111242",
+    "121142", "121241", "114212", "124112", "124211", "411212", "421112", "421211", "212141",
+    "214121", "412121", "111143", "111341", "131141", "114113", "114311", "411113", "411311",
+    "113141", "114131", "311141", "411131", "211412", "211214", "211232", "2331112",
+];
+const CODE128_START_B: usize = 104;
+const CODE128_STOP: usize = 106;
+
+fn code128_modules(data: &str) -> Vec<bool> {
+    // Code set B maps printable ASCII straight to values
+    let values: Vec<usize> = data.bytes().map(|b| (b - 0x20) as usize).collect();
+    let weighted: usize = values.iter().enumerate().map(|(i, v)| (i + 1) * v).sum();
+    let checksum = (CODE128_START_B + weighted) % 103;
+
+    let symbols = once(CODE128_START_B)
+        .chain(values)
+        .chain(once(checksum))
+        .chain(once(CODE128_STOP));
+
+    let mut out = Vec::new();
+    for symbol in symbols {
+        for (i, width) in CODE128[symbol].bytes().enumerate() {
+            let bar = i % 2 == 0;
+            for _ in 0..width - b'0' {
+                out.push(bar);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_digits() {
+        let ean = Barcode::new(Symbology::Ean13, "400638133393").unwrap();
+        assert_eq!(ean.data(), "4006381333931");
+        assert!(Barcode::new(Symbology::Ean13, "4006381333931").is_ok());
+        assert!(Barcode::new(Symbology::Ean13, "4006381333932").is_err());
+        assert!(Barcode::new(Symbology::Ean13, "40063813339").is_err());
+        assert!(Barcode::new(Symbology::Ean13, "40063813339a").is_err());
+
+        let upc = Barcode::new(Symbology::UpcA, "03600029145").unwrap();
+        assert_eq!(upc.data(), "036000291452");
+
+        assert!(Barcode::new(Symbology::Code128, "Hello, world!").is_ok());
+        assert!(Barcode::new(Symbology::Code128, "caf\u{e9}").is_err());
+        assert!(Barcode::new(Symbology::Code128, &"x".repeat(40)).is_err());
+    }
+
+    #[test]
+    fn test_modules() {
+        for (value, widths) in CODE128.iter().enumerate() {
+            let total: u32 = widths.bytes().map(|w| (w - b'0') as u32).sum();
+            assert_eq!(total, if value == CODE128_STOP { 13 } else { 11 });
+        }
+
+        let code = Barcode::new(Symbology::Code128, "Wiki").unwrap();
+        assert_eq!(code.modules().len(), 11 * (4 + 3) + 2);
+
+        // Guard bars, and the first digit's parity which is only carried by the left half
+        let ean = Barcode::new(Symbology::Ean13, "400638133393").unwrap().modules();
+        assert_eq!(ean.len(), 95);
+        let modules = |code: &Barcode| -> String {
+            code.modules()
+                .iter()
+                .map(|&m| if m { '1' } else { '0' })
+                .collect()
+        };
+        // Reference from the GS1 tables, with the first digit 6 giving LGGGLL parity
+        let six = Barcode::new(Symbology::Ean13, "690123456789").unwrap();
+        assert_eq!(six.data(), "6901234567892");
+        assert_eq!(
+            modules(&six),
+            "10100010110100111011001100110110111101010001101010100111010100001000100100100011101001101100101"
+        );
+        assert_eq!(&ean[..3], &[true, false, true]);
+        assert_eq!(&ean[45..50], &[false, true, false, true, false]);
+        let upc = Barcode::new(Symbology::UpcA, "03600029145").unwrap();
+        let as_ean = Barcode::new(Symbology::Ean13, "0036000291452").unwrap();
+        assert_eq!(upc.modules(), as_ean.modules());
+
+        let image = code.image();
+        assert!(image.width() <= PRINTER_DOTS_PER_LINE);
+        assert_eq!(image.get_pixel(0, 0), &Rgb([0xFF; 3]));
+        assert_eq!(image.get_pixel(QUIET_ZONE * code.module_dots(), 0), &Rgb([0x00; 3]));
+
+        // The text is drawn below the bars, as the printer would for native barcodes
+        assert_eq!(image.height(), BARCODE_HEIGHT + TEXT_GAP + GLYPH_HEIGHT);
+        let text = (BARCODE_HEIGHT..image.height())
+            .flat_map(|y| (0..image.width()).map(move |x| (x, y)))
+            .filter(|&(x, y)| image.get_pixel(x, y) == &Rgb([0x00; 3]))
+            .count();
+        assert!(text > 0);
+    }
+
+    #[test]
+    fn test_escpos() {
+        let code = Barcode::new(Symbology::Code128, "a{b").unwrap();
+        assert!(code.escpos().ends_with(&[GS, b'k', 73, 6, b'{', b'B', b'a', b'{', b'{', b'b']));
+        let ean = Barcode::new(Symbology::Ean13, "400638133393").unwrap();
+        assert!(ean.escpos().ends_with(b"\x1dk\x43\x0d4006381333931"));
+    }
+}
@@ -0,0 +1,368 @@
+//! Canvases for Lua scripts to draw on. `Canvas.new(w, h)` makes a white canvas, which is drawn
+//! on with `pixel`, `line`, `rect`, `circle`, `fill`, `text` and `blit`, and printed with
+//! `canvas:print(options)`. Coordinates count from 0 at the top left. Colours are `true` for
+//! black, `false` for white or a brightness from 0 to 255, and black when left out.
+
+use anyhow::{ensure, Result};
+use image::{GrayImage, Luma};
+use mlua::{AnyUserData, UserData, UserDataMethods, Value};
+
+use crate::emulator::{font_dot, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::imaging::MAX_LENGTH;
+
+/// Registry key of the `image` builtin, which `canvas:print()` hands the canvas to
+pub const PRINT_IMAGE: &str = "print_image";
+/// Largest text scale, 16 makes 192 by 384 dot letters
+const MAX_TEXT_SIZE: u32 = 16;
+/// Coordinates further out than this are refused, so drawing never takes long
+const MAX_COORD: f64 = 65536.;
+
+pub struct Canvas {
+    image: GrayImage,
+}
+
+impl Canvas {
+    /// A white canvas
+    pub fn new(width: u32, height: u32) -> Result<Self> {
+        Self::from_image(GrayImage::from_pixel(width, height, Luma([0xFF])))
+    }
+
+    pub fn from_image(image: GrayImage) -> Result<Self> {
+        let (width, height) = image.dimensions();
+        ensure!(
+            (1..=MAX_LENGTH).contains(&width) && (1..=MAX_LENGTH).contains(&height),
+            "Canvases can be 1 to {} pixels on a side",
+            MAX_LENGTH
+        );
+        Ok(Self { image })
+    }
+
+    pub fn image(&self) -> &GrayImage {
+        &self.image
+    }
+
+    /// Brightness of a pixel, if it's on the canvas
+    pub fn pixel(&self, x: i64, y: i64) -> Option<u8> {
+        self.contains(x, y)
+            .then(|| self.image.get_pixel(x as u32, y as u32).0[0])
+    }
+
+    /// Set a pixel. Anything off the canvas is ignored, as with all drawing.
+    pub fn set_pixel(&mut self, x: i64, y: i64, color: u8) {
+        if self.contains(x, y) {
+            self.image.put_pixel(x as u32, y as u32, Luma([color]));
+        }
+    }
+
+    fn contains(&self, x: i64, y: i64) -> bool {
+        (0..self.image.width() as i64).contains(&x) && (0..self.image.height() as i64).contains(&y)
+    }
+
+    /// Straight line between two points, including both ends
+    pub fn line(&mut self, (x0, y0): (i64, i64), (x1, y1): (i64, i64), color: u8) {
+        // Bresenham's algorithm
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = ((x1 - x0).signum(), (y1 - y0).signum());
+        let (mut x, mut y, mut err) = (x0, y0, dx + dy);
+        loop {
+            self.set_pixel(x, y, color);
+            if (x, y) == (x1, y1) {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    /// Rectangle with its top left corner at `x, y`
+    pub fn rect(&mut self, x: i64, y: i64, width: i64, height: i64, color: u8, filled: bool) {
+        if width <= 0 || height <= 0 {
+            return;
+        }
+        let (right, bottom) = (x + width - 1, y + height - 1);
+        match filled {
+            true => self.fill_rows(y..=bottom, |_| (x, right), color),
+            false => {
+                self.line((x, y), (right, y), color);
+                self.line((x, bottom), (right, bottom), color);
+                self.line((x, y), (x, bottom), color);
+                self.line((right, y), (right, bottom), color);
+            }
+        }
+    }
+
+    /// Circle centred on `x, y`
+    pub fn circle(&mut self, x: i64, y: i64, radius: i64, color: u8, filled: bool) {
+        if radius < 0 {
+            return;
+        }
+        // Half the width of the circle at each row, measured to pixel centres
+        let r = radius as f64 + 0.5;
+        let half_width = |dy: i64| (r * r - (dy * dy) as f64).sqrt() as i64;
+        if filled {
+            let span = |row| (x - half_width(row - y), x + half_width(row - y));
+            return self.fill_rows(y - radius..=y + radius, span, color);
+        }
+
+        // Outlines are the pixels of the filled circle next to one outside it
+        let inside = |dx: i64, dy: i64| dy.abs() <= radius && dx.abs() <= half_width(dy);
+        let (width, height) = (self.image.width() as i64, self.image.height() as i64);
+        for py in (y - radius).max(0)..=(y + radius).min(height - 1) {
+            let half = half_width(py - y);
+            for px in (x - half).max(0)..=(x + half).min(width - 1) {
+                let (dx, dy) = (px - x, py - y);
+                let edge = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                    .iter()
+                    .any(|(nx, ny)| !inside(dx + nx, dy + ny));
+                if edge {
+                    self.image.put_pixel(px as u32, py as u32, Luma([color]));
+                }
+            }
+        }
+    }
+
+    /// Fill each row in a range between the two columns given for it
+    fn fill_rows(
+        &mut self,
+        rows: std::ops::RangeInclusive<i64>,
+        span: impl Fn(i64) -> (i64, i64),
+        color: u8,
+    ) {
+        let (width, height) = (self.image.width() as i64, self.image.height() as i64);
+        for y in (*rows.start()).max(0)..=(*rows.end()).min(height - 1) {
+            let (left, right) = span(y);
+            for x in left.max(0)..=right.min(width - 1) {
+                self.image.put_pixel(x as u32, y as u32, Luma([color]));
+            }
+        }
+    }
+
+    /// Fill the whole canvas
+    pub fn fill(&mut self, color: u8) {
+        for pixel in self.image.pixels_mut() {
+            *pixel = Luma([color]);
+        }
+    }
+
+    /// Write text in the printer's own font, scaled up `size` times, with its top left corner at
+    /// `x, y`. Newlines start a new line under the first.
+    pub fn text(&mut self, x: i64, y: i64, text: &str, size: u32, color: u8) {
+        let size = size.clamp(1, MAX_TEXT_SIZE) as i64;
+        let (glyph_width, glyph_height) = (GLYPH_WIDTH as i64, GLYPH_HEIGHT as i64);
+        let (mut left, mut top) = (x, y);
+        for c in text.chars() {
+            if c == '\n' {
+                left = x;
+                top += glyph_height * size;
+                continue;
+            }
+            let c = match c.is_ascii() {
+                true => c as u8,
+                false => b'?',
+            };
+            // Only letters on the canvas are drawn, so long text off the edge costs nothing
+            let (right, bottom) = (left + glyph_width * size, top + glyph_height * size);
+            if right > 0 && bottom > 0 && self.contains(left.max(0), top.max(0)) {
+                for gy in 0..GLYPH_HEIGHT {
+                    for gx in (0..GLYPH_WIDTH).filter(|&gx| font_dot(c, gx, gy)) {
+                        let (px, py) = (left + gx as i64 * size, top + gy as i64 * size);
+                        self.fill_rows(py..=py + size - 1, |_| (px, px + size - 1), color);
+                    }
+                }
+            }
+            left += glyph_width * size;
+        }
+    }
+
+    /// Copy another canvas onto this one, with its top left corner at `x, y`
+    pub fn blit(&mut self, other: &GrayImage, x: i64, y: i64) {
+        for (ox, oy, pixel) in other.enumerate_pixels() {
+            self.set_pixel(x + ox as i64, y + oy as i64, pixel.0[0]);
+        }
+    }
+}
+
+/// A pixel given as `true` for black, `false` for white, or a brightness from 0 to 255
+pub fn brightness(value: &Value) -> Option<u8> {
+    match value {
+        Value::Boolean(true) => Some(0x00),
+        Value::Boolean(false) => Some(0xFF),
+        Value::Integer(v) => Some((*v).clamp(0, 0xFF) as u8),
+        Value::Number(v) => Some(v.clamp(0., 255.) as u8),
+        _ => None,
+    }
+}
+
+/// A colour argument, black if left out
+fn color(value: Value) -> mlua::Result<u8> {
+    match value {
+        Value::Nil => Ok(0x00),
+        value => brightness(&value).ok_or_else(|| {
+            mlua::Error::RuntimeError(
+                "Colours should be true, false or a brightness from 0 to 255".into(),
+            )
+        }),
+    }
+}
+
+/// A coordinate or size argument, rounded to the nearest pixel
+fn coord(value: f64) -> mlua::Result<i64> {
+    match value.abs() <= MAX_COORD {
+        true => Ok(value.round() as i64),
+        false => Err(mlua::Error::RuntimeError(format!(
+            "Coordinates should be between -{0} and {0}",
+            MAX_COORD
+        ))),
+    }
+}
+
+impl UserData for Canvas {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("width", |_, this, ()| Ok(this.image.width()));
+        methods.add_method("height", |_, this, ()| Ok(this.image.height()));
+
+        // Reads the pixel when no colour is given
+        methods.add_method_mut("pixel", |_, this, (x, y, value): (f64, f64, Value)| {
+            let (x, y) = (coord(x)?, coord(y)?);
+            match value {
+                Value::Nil => Ok(this.pixel(x, y)),
+                value => {
+                    this.set_pixel(x, y, color(value)?);
+                    Ok(None)
+                }
+            }
+        });
+        methods.add_method_mut(
+            "line",
+            |_, this, (x0, y0, x1, y1, value): (f64, f64, f64, f64, Value)| {
+                let (start, end) = ((coord(x0)?, coord(y0)?), (coord(x1)?, coord(y1)?));
+                this.line(start, end, color(value)?);
+                Ok(())
+            },
+        );
+        methods.add_method_mut(
+            "rect",
+            |_, this, (x, y, w, h, value, filled): (f64, f64, f64, f64, Value, Option<bool>)| {
+                let (x, y, w, h) = (coord(x)?, coord(y)?, coord(w)?, coord(h)?);
+                this.rect(x, y, w, h, color(value)?, filled.unwrap_or(false));
+                Ok(())
+            },
+        );
+        methods.add_method_mut(
+            "circle",
+            |_, this, (x, y, r, value, filled): (f64, f64, f64, Value, Option<bool>)| {
+                let (x, y, r) = (coord(x)?, coord(y)?, coord(r)?);
+                this.circle(x, y, r, color(value)?, filled.unwrap_or(false));
+                Ok(())
+            },
+        );
+        methods.add_method_mut("fill", |_, this, value: Value| {
+            this.fill(color(value)?);
+            Ok(())
+        });
+        methods.add_method_mut(
+            "text",
+            |_, this, (x, y, text, size, value): (f64, f64, String, Option<u32>, Value)| {
+                this.text(
+                    coord(x)?,
+                    coord(y)?,
+                    &text,
+                    size.unwrap_or(1),
+                    color(value)?,
+                );
+                Ok(())
+            },
+        );
+        methods.add_method_mut("blit", |_, this, (other, x, y): (AnyUserData, f64, f64)| {
+            let other = other.borrow::<Canvas>()?;
+            this.blit(&other.image, coord(x)?, coord(y)?);
+            Ok(())
+        });
+
+        methods.add_function("print", |lua, (this, options): (AnyUserData, Value)| {
+            let print_image: mlua::Function = lua.named_registry_value(PRINT_IMAGE)?;
+            print_image.call::<_, ()>((this, options))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rows of the canvas, `#` for black
+    fn rows(canvas: &Canvas) -> Vec<String> {
+        let image = canvas.image();
+        (0..image.height())
+            .map(|y| {
+                (0..image.width())
+                    .map(|x| match image.get_pixel(x, y).0[0] < 0x80 {
+                        true => '#',
+                        false => '.',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_shapes() {
+        assert!(Canvas::new(0, 10).is_err());
+        assert!(Canvas::new(10, MAX_LENGTH + 1).is_err());
+
+        let mut canvas = Canvas::new(7, 5).unwrap();
+        canvas.line((0, 0), (6, 4), 0);
+        canvas.set_pixel(-1, 2, 0);
+        assert_eq!(canvas.pixel(3, 2), Some(0));
+        assert_eq!(canvas.pixel(7, 0), None);
+        assert_eq!(
+            rows(&canvas),
+            ["#......", ".##....", "...#...", "....##.", "......#"]
+        );
+
+        canvas.fill(0xFF);
+        canvas.rect(1, 1, 4, 3, 0, false);
+        canvas.rect(5, -2, 10, 4, 0, true);
+        assert_eq!(
+            rows(&canvas),
+            [".....##", ".######", ".#..#..", ".####..", "......."]
+        );
+
+        let mut canvas = Canvas::new(7, 7).unwrap();
+        canvas.circle(3, 3, 3, 0, true);
+        assert_eq!(
+            rows(&canvas),
+            ["..###..", ".#####.", "#######", "#######", "#######", ".#####.", "..###.."]
+        );
+        canvas.fill(0xFF);
+        canvas.circle(3, 3, 3, 0, false);
+        assert_eq!(
+            rows(&canvas),
+            ["..###..", ".#...#.", "#.....#", "#.....#", "#.....#", ".#...#.", "..###.."]
+        );
+    }
+
+    #[test]
+    fn test_text_and_blit() {
+        let mut letter = Canvas::new(GLYPH_WIDTH * 2, GLYPH_HEIGHT * 2).unwrap();
+        letter.text(0, 0, "I", 2, 0);
+        let dots = |canvas: &Canvas| canvas.image().pixels().filter(|p| p.0[0] == 0).count();
+        let single = (0..GLYPH_HEIGHT)
+            .flat_map(|y| (0..GLYPH_WIDTH).map(move |x| (x, y)))
+            .filter(|&(x, y)| font_dot(b'I', x, y))
+            .count();
+        assert_eq!(dots(&letter), single * 4);
+
+        // Copies land where they're put, and are cut off at the edge
+        let mut canvas = Canvas::new(GLYPH_WIDTH * 3, GLYPH_HEIGHT * 2).unwrap();
+        canvas.blit(letter.image(), GLYPH_WIDTH as i64 * 2, 0);
+        assert_eq!(dots(&canvas), single * 2);
+    }
+}
@@ -0,0 +1,317 @@
+//! Character sets of the printer's own font. Besides ASCII, ESC/POS printers have a handful of
+//! code pages selected with ESC t. Each line is encoded into whichever supported code page fits
+//! it best, with typographic punctuation swapped for plain ASCII where a code page lacks it.
+
+use anyhow::{format_err, Result};
+use std::str::FromStr;
+
+use crate::escpos::ESC;
+use crate::layout::Line;
+use crate::markup::Style;
+
+/// Code pages with a known ESC t number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodePage {
+    Cp437,
+    Cp850,
+    Cp852,
+    Cp858,
+    Cp866,
+    Cp1252,
+}
+
+impl CodePage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CodePage::Cp437 => "cp437",
+            CodePage::Cp850 => "cp850",
+            CodePage::Cp852 => "cp852",
+            CodePage::Cp858 => "cp858",
+            CodePage::Cp866 => "cp866",
+            CodePage::Cp1252 => "cp1252",
+        }
+    }
+
+    /// ESC t command selecting this code page, using Epson's numbering
+    pub fn select(&self) -> [u8; 3] {
+        let number = match self {
+            CodePage::Cp437 => 0,
+            CodePage::Cp850 => 2,
+            CodePage::Cp1252 => 16,
+            CodePage::Cp866 => 17,
+            CodePage::Cp852 => 18,
+            CodePage::Cp858 => 19,
+        };
+        [ESC, b't', number]
+    }
+
+    /// Characters 0x80 to 0xFF
+    fn upper_half(&self) -> &'static str {
+        match self {
+            CodePage::Cp437 => CP437,
+            CodePage::Cp850 => CP850,
+            CodePage::Cp852 => CP852,
+            CodePage::Cp858 => CP858,
+            CodePage::Cp866 => CP866,
+            CodePage::Cp1252 => CP1252,
+        }
+    }
+}
+
+impl FromStr for CodePage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().trim_start_matches("cp") {
+            "437" => Ok(CodePage::Cp437),
+            "850" => Ok(CodePage::Cp850),
+            "852" => Ok(CodePage::Cp852),
+            "858" => Ok(CodePage::Cp858),
+            "866" => Ok(CodePage::Cp866),
+            "1252" => Ok(CodePage::Cp1252),
+            _ => Err(format_err!(
+                "Unknown code page {}, expected one of cp437, cp850, cp852, cp858, cp866, cp1252",
+                s
+            )),
+        }
+    }
+}
+
+/// A line encoded for the printer's font
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedLine {
+    /// Code page to select first, or `None` for plain ASCII
+    pub code_page: Option<CodePage>,
+    pub spans: Vec<(Style, Vec<u8>)>,
+}
+
+/// Encode a line into the code page needing the fewest substitutions, preferring those listed
+/// first. `None` if no single code page has every character.
+pub fn encode(line: &Line, code_pages: &[CodePage]) -> Option<EncodedLine> {
+    let candidates = std::iter::once(None).chain(code_pages.iter().copied().map(Some));
+    let mut best: Option<(usize, EncodedLine)> = None;
+    for code_page in candidates {
+        let mut substitutions = 0;
+        let spans: Option<Vec<_>> = line
+            .iter()
+            .map(|span| {
+                Some((
+                    span.style,
+                    encode_text(code_page, &span.text, &mut substitutions)?,
+                ))
+            })
+            .collect();
+        let better = match &best {
+            Some((fewest, _)) => substitutions < *fewest,
+            None => true,
+        };
+        if let (Some(spans), true) = (spans, better) {
+            best = Some((substitutions, EncodedLine { code_page, spans }));
+        }
+    }
+    best.map(|(_, line)| line)
+}
+
+/// Encode text, counting the characters which had to be substituted
+fn encode_text(
+    code_page: Option<CodePage>,
+    text: &str,
+    substitutions: &mut usize,
+) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        match encode_char(code_page, c) {
+            Some(b) => bytes.push(b),
+            None => {
+                *substitutions += 1;
+                bytes.push(substitute(c)? as u8);
+            }
+        }
+    }
+    Some(bytes)
+}
+
+fn encode_char(code_page: Option<CodePage>, c: char) -> Option<u8> {
+    if c.is_ascii() {
+        return Some(c as u8);
+    }
+    let idx = code_page?.upper_half().chars().position(|x| x == c)?;
+    Some(0x80 + idx as u8)
+}
+
+/// ASCII stand-ins for punctuation a code page might not have
+fn substitute(c: char) -> Option<char> {
+    match c {
+        '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}' | '\u{2032}' => Some('\''),
+        '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{201F}' | '\u{2033}' | '«' | '»' => Some('"'),
+        '\u{2039}' => Some('<'),
+        '\u{203A}' => Some('>'),
+        '\u{AD}' | '\u{2010}'..='\u{2015}' | '\u{2212}' => Some('-'),
+        '\u{2022}' | '\u{2043}' => Some('*'),
+        '\u{A0}' | '\u{2000}'..='\u{200A}' | '\u{202F}' => Some(' '),
+        _ => None,
+    }
+}
+
+/// Spell out ligatures and ellipses in ASCII. These take up more than one column once replaced,
+/// so unlike other substitutions this has to happen before layout, whatever the code page.
+pub fn spell_out(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\u{2026}' => out.push_str("..."),
+            '\u{FB00}' => out.push_str("ff"),
+            '\u{FB01}' => out.push_str("fi"),
+            '\u{FB02}' => out.push_str("fl"),
+            '\u{FB03}' => out.push_str("ffi"),
+            '\u{FB04}' => out.push_str("ffl"),
+            '\u{FB05}' | '\u{FB06}' => out.push_str("st"),
+            '\u{132}' => out.push_str("IJ"),
+            '\u{133}' => out.push_str("ij"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+const CP437: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅ",
+    "ÉæÆôöòûùÿÖÜ¢£¥₧ƒ",
+    "áíóúñÑªº¿⌐¬½¼¡«»",
+    "░▒▓│┤╡╢╖╕╣║╗╝╜╛┐",
+    "└┴┬├─┼╞╟╚╔╩╦╠═╬╧",
+    "╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀",
+    "αßΓπΣσµτΦΘΩδ∞φε∩",
+    "≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}",
+);
+
+const CP850: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅ",
+    "ÉæÆôöòûùÿÖÜø£Ø×ƒ",
+    "áíóúñÑªº¿®¬½¼¡«»",
+    "░▒▓│┤ÁÂÀ©╣║╗╝¢¥┐",
+    "└┴┬├─┼ãÃ╚╔╩╦╠═╬¤",
+    "ðÐÊËÈıÍÎÏ┘┌█▄¦Ì▀",
+    "ÓßÔÒõÕµþÞÚÛÙýÝ¯´",
+    "\u{ad}±‗¾¶§÷¸°¨·¹³²■\u{a0}",
+);
+
+const CP852: &str = concat!(
+    "ÇüéâäůćçłëŐőîŹÄĆ",
+    "ÉĹĺôöĽľŚśÖÜŤťŁ×č",
+    "áíóúĄąŽžĘę¬źČş«»",
+    "░▒▓│┤ÁÂĚŞ╣║╗╝Żż┐",
+    "└┴┬├─┼Ăă╚╔╩╦╠═╬¤",
+    "đĐĎËďŇÍÎě┘┌█▄ŢŮ▀",
+    "ÓßÔŃńňŠšŔÚŕŰýÝţ´",
+    "\u{ad}˝˛ˇ˘§÷¸°¨˙űŘř■\u{a0}",
+);
+
+/// CP850 with the euro sign in place of the dotless i
+const CP858: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅ",
+    "ÉæÆôöòûùÿÖÜø£Ø×ƒ",
+    "áíóúñÑªº¿®¬½¼¡«»",
+    "░▒▓│┤ÁÂÀ©╣║╗╝¢¥┐",
+    "└┴┬├─┼ãÃ╚╔╩╦╠═╬¤",
+    "ðÐÊËÈ€ÍÎÏ┘┌█▄¦Ì▀",
+    "ÓßÔÒõÕµþÞÚÛÙýÝ¯´",
+    "\u{ad}±‗¾¶§÷¸°¨·¹³²■\u{a0}",
+);
+
+const CP866: &str = concat!(
+    "АБВГДЕЖЗИЙКЛМНОП",
+    "РСТУФХЦЧШЩЪЫЬЭЮЯ",
+    "абвгдежзийклмноп",
+    "░▒▓│┤╡╢╖╕╣║╗╝╜╛┐",
+    "└┴┬├─┼╞╟╚╔╩╦╠═╬╧",
+    "╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀",
+    "рстуфхцчшщъыьэюя",
+    "ЁёЄєЇїЎў°∙·√№¤■\u{a0}",
+);
+
+/// Windows-1252. The five unassigned positions are filled with NUL, which never matches as it's
+/// ASCII.
+const CP1252: &str = concat!(
+    "€\0‚ƒ„…†‡ˆ‰Š‹Œ\0Ž\0",
+    "\0‘’“”•–—˜™š›œ\0žŸ",
+    "\u{a0}¡¢£¤¥¦§¨©ª«¬\u{ad}®¯",
+    "°±²³´µ¶·¸¹º»¼½¾¿",
+    "ÀÁÂÃÄÅÆÇÈÉÊËÌÍÎÏ",
+    "ÐÑÒÓÔÕÖ×ØÙÚÛÜÝÞß",
+    "àáâãäåæçèéêëìíîï",
+    "ðñòóôõö÷øùúûüýþÿ",
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markup::Span;
+
+    const ALL: [CodePage; 6] = [
+        CodePage::Cp437,
+        CodePage::Cp850,
+        CodePage::Cp852,
+        CodePage::Cp858,
+        CodePage::Cp866,
+        CodePage::Cp1252,
+    ];
+
+    fn line(text: &str) -> Line {
+        vec![Span {
+            text: text.into(),
+            style: Style::default(),
+        }]
+    }
+
+    #[test]
+    fn test_tables() {
+        for code_page in &ALL {
+            assert_eq!(
+                code_page.upper_half().chars().count(),
+                128,
+                "{:?}",
+                code_page
+            );
+            assert_eq!(code_page.as_str().parse::<CodePage>().unwrap(), *code_page);
+        }
+        assert_eq!(encode_char(Some(CodePage::Cp437), '\u{e9}'), Some(0x82));
+        assert_eq!(encode_char(Some(CodePage::Cp858), '\u{20ac}'), Some(0xD5));
+        assert_eq!(encode_char(Some(CodePage::Cp1252), '\u{20ac}'), Some(0x80));
+        assert_eq!(encode_char(Some(CodePage::Cp866), '\u{44f}'), Some(0xEF));
+        assert_eq!(encode_char(None, '\u{e9}'), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        let pages = [CodePage::Cp437, CodePage::Cp852, CodePage::Cp1252];
+
+        // ASCII doesn't need a code page
+        let ascii = encode(&line("hi"), &pages).unwrap();
+        assert_eq!(ascii.code_page, None);
+        assert_eq!(ascii.spans[0].1, b"hi");
+
+        // The first code page with everything wins
+        let accents = encode(&line("caf\u{e9}"), &pages).unwrap();
+        assert_eq!(accents.code_page, Some(CodePage::Cp437));
+        assert_eq!(accents.spans[0].1, b"caf\x82");
+        let polish = encode(&line("\u{142}\u{f3}d\u{17a}"), &pages).unwrap();
+        assert_eq!(polish.code_page, Some(CodePage::Cp852));
+
+        // Smart quotes are native in CP1252, and substituted otherwise
+        let quoted = encode(&line("\u{201c}hi\u{201d}"), &pages).unwrap();
+        assert_eq!(quoted.code_page, Some(CodePage::Cp1252));
+        assert_eq!(quoted.spans[0].1, b"\x93hi\x94");
+        let quoted = encode(
+            &line("\u{201c}caf\u{e9}\u{201d} \u{2014}"),
+            &[CodePage::Cp437],
+        )
+        .unwrap();
+        assert_eq!(quoted.spans[0].1, b"\"caf\x82\" -");
+
+        // Nothing has both Cyrillic and Polish, or CJK at all
+        assert_eq!(encode(&line("\u{44f} \u{142}"), &ALL), None);
+        assert_eq!(encode(&line("\u{65e5}"), &ALL), None);
+        assert_eq!(spell_out("\u{fb01}ne\u{2026}"), "fine...");
+    }
+}
@@ -0,0 +1,200 @@
+//! SVG and PDF attachments, rendered to pictures for `imaging` to dither. SVGs are drawn with
+//! resvg, and PDFs by poppler's `pdftoppm`, which has to be installed.
+
+use anyhow::{bail, format_err, Context, Result};
+use image::{DynamicImage, RgbaImage};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender, SyncSender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::imaging::MAX_LENGTH;
+use crate::printer::PRINTER_DOTS_PER_LINE;
+
+/// Most pages printed from one PDF
+pub const MAX_PAGES: u32 = 5;
+/// How long `pdftoppm` gets to render them, or resvg an SVG
+const RENDER_TIMEOUT: Duration = Duration::from_secs(30);
+/// SVGs waiting to be drawn, beyond which more are turned away
+const SVG_QUEUE: usize = 4;
+
+/// An SVG for the SVG thread, and where to send the picture
+type SvgRequest = (Vec<u8>, Sender<Result<DynamicImage>>);
+
+/// The SVG thread, started by the first SVG
+static SVG_THREAD: OnceLock<SyncSender<SvgRequest>> = OnceLock::new();
+
+/// Attachments which are rendered to images before printing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocumentKind {
+    Svg,
+    Pdf,
+}
+
+impl DocumentKind {
+    /// Recognise a document by its file extension
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        let extension = name[name.rfind('.')? + 1..].to_ascii_lowercase();
+        match extension.as_str() {
+            "svg" => Some(Self::Svg),
+            "pdf" => Some(Self::Pdf),
+            _ => None,
+        }
+    }
+}
+
+/// Draw an SVG as wide as the paper, on white. resvg can't be interrupted, so SVGs are drawn one
+/// at a time on a thread of their own, which is given up on after `RENDER_TIMEOUT`.
+pub fn render_svg(data: &[u8]) -> Result<DynamicImage> {
+    let svg_thread = SVG_THREAD.get_or_init(|| {
+        let (tx, rx) = mpsc::sync_channel::<SvgRequest>(SVG_QUEUE);
+        thread::spawn(move || {
+            // Finding the system fonts is slow, so it's only done once
+            let mut opt = usvg::Options::default();
+            opt.fontdb.load_system_fonts();
+            // Images have to be embedded as data: URLs. Anything else is a path on this machine.
+            opt.image_href_resolver.resolve_string = Box::new(|_, _| None);
+            for (data, reply) in rx {
+                let image = panic::catch_unwind(AssertUnwindSafe(|| draw_svg(&data, &opt)))
+                    .unwrap_or_else(|_| Err(format_err!("SVG render failed")));
+                let _ = reply.send(image);
+            }
+        });
+        tx
+    });
+
+    let (tx, rx) = mpsc::channel();
+    svg_thread
+        .try_send((data.to_vec(), tx))
+        .map_err(|_| format_err!("Too many SVGs waiting to print, try again later"))?;
+    match rx.recv_timeout(RENDER_TIMEOUT) {
+        Ok(image) => image,
+        Err(RecvTimeoutError::Timeout) => bail!("SVG took too long to render"),
+        Err(RecvTimeoutError::Disconnected) => bail!("SVG render failed"),
+    }
+}
+
+/// Parse and draw an SVG, on the SVG thread
+fn draw_svg(data: &[u8], opt: &usvg::Options) -> Result<DynamicImage> {
+    let tree = usvg::Tree::from_data(data, &opt.to_ref()).context("SVG parse failed")?;
+
+    let fit = usvg::FitTo::Size(PRINTER_DOTS_PER_LINE, MAX_LENGTH);
+    let size = fit
+        .fit_to(tree.svg_node().size.to_screen_size())
+        .context("SVG has no size")?;
+    let mut pixmap =
+        tiny_skia::Pixmap::new(size.width(), size.height()).context("SVG has no size")?;
+    pixmap.fill(tiny_skia::Color::WHITE);
+    resvg::render(&tree, fit, tiny_skia::Transform::default(), pixmap.as_mut())
+        .context("SVG render failed")?;
+
+    // Premultiplied alpha is the same as straight alpha once everything is opaque
+    let image = RgbaImage::from_raw(size.width(), size.height(), pixmap.take())
+        .context("SVG render failed")?;
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+/// Draw the first `MAX_PAGES` pages of a PDF as wide as the paper
+pub fn render_pdf(data: &[u8]) -> Result<Vec<DynamicImage>> {
+    let dir = tempfile::tempdir()?;
+    let input = dir.path().join("input.pdf");
+    fs::write(&input, data)?;
+
+    let width = PRINTER_DOTS_PER_LINE.to_string();
+    let last_page = MAX_PAGES.to_string();
+    let mut child = Command::new("pdftoppm")
+        .args(["-png", "-f", "1", "-l", &last_page])
+        .args(["-scale-to-x", &width, "-scale-to-y", "-1"])
+        .arg(&input)
+        .arg(dir.path().join("page"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Couldn't run pdftoppm, is poppler installed?")?;
+
+    // Malicious PDFs can take forever
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() > RENDER_TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("PDF took too long to render");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    if !status.success() {
+        bail!("PDF render failed: pdftoppm {}", status);
+    }
+
+    // Pages are named page-1.png, or page-01.png and so on for longer documents
+    let mut pages: Vec<_> = fs::read_dir(dir.path())?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension() == Some("png".as_ref()))
+        .collect();
+    pages.sort();
+    if pages.is_empty() {
+        bail!("PDF has no pages");
+    }
+    pages
+        .iter()
+        .map(|page| image::open(page).context("PDF page unreadable"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_kind() {
+        let kind = DocumentKind::from_file_name;
+        assert_eq!(kind("ticket.PDF"), Some(DocumentKind::Pdf));
+        assert_eq!(kind("logo.svg"), Some(DocumentKind::Svg));
+        assert_eq!(kind("svg"), None);
+        assert_eq!(kind("photo.png"), None);
+    }
+
+    #[test]
+    fn test_render_svg() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="20">
+            <rect x="20" width="20" height="20" fill="black"/>
+        </svg>"#;
+        let image = render_svg(svg).unwrap().to_luma8();
+        assert_eq!(
+            image.dimensions(),
+            (PRINTER_DOTS_PER_LINE, PRINTER_DOTS_PER_LINE / 2)
+        );
+        // Transparent areas come out white
+        assert_eq!(image.get_pixel(10, 10).0, [0xFF]);
+        assert_eq!(image.get_pixel(300, 100).0, [0x00]);
+
+        assert!(render_svg(b"<html></html>").is_err());
+    }
+
+    #[test]
+    fn test_svg_local_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret.png");
+        RgbaImage::from_pixel(4, 4, image::Rgba([0, 0, 0, 0xFF]))
+            .save(&path)
+            .unwrap();
+
+        let svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"
+                width="40" height="20">
+                <image href="{0}" width="20" height="20"/>
+                <image xlink:href="file://{0}" x="20" width="20" height="20"/>
+            </svg>"#,
+            path.display()
+        );
+        let image = render_svg(svg.as_bytes()).unwrap().to_luma8();
+        assert!(image.pixels().all(|p| p.0 == [0xFF]));
+    }
+}
@@ -0,0 +1,529 @@
+use anyhow::Result;
+use image::{GrayImage, Luma};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::backend::{PrinterBackend, PrinterStatus};
+use crate::escpos::{CR, DLE, ESC, FS, GS, LF};
+use crate::printer::{PRINTER_DOTS_PER_LINE, PRINTER_DOTS_PER_MM, PRINTER_LINE_DOTS};
+
+/// 12x24 dot font for printable ASCII, rendered from DejaVu Sans Mono. Each row is a big-endian u16.
+const FONT: &[u8] = include_bytes!("../assets/font12x24.bin");
+pub const GLYPH_WIDTH: u32 = 12;
+pub const GLYPH_HEIGHT: u32 = 24;
+const DEFAULT_LINE_SPACING: u32 = PRINTER_LINE_DOTS;
+/// Longest roll the emulator keeps, in rows. Older paper is torn off the top.
+const MAX_ROLL_ROWS: u32 = 1000 * PRINTER_DOTS_PER_MM;
+
+const WHITE: Luma<u8> = Luma([0xFF]);
+const BLACK: Luma<u8> = Luma([0x00]);
+
+/// Whether a dot of a character in the bitmap font is black. Unprintable characters are drawn
+/// as `?`.
+pub fn font_dot(c: u8, x: u32, y: u32) -> bool {
+    let idx = match c {
+        0x20..=0x7E => (c - 0x20) as usize,
+        _ => (b'?' - 0x20) as usize,
+    };
+    let rows = &FONT[idx * GLYPH_HEIGHT as usize * 2..][..GLYPH_HEIGHT as usize * 2];
+    let row = u16::from_be_bytes([rows[y as usize * 2], rows[y as usize * 2 + 1]]);
+    x < GLYPH_WIDTH && row & (0x8000 >> x) != 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Style {
+    bold: bool,
+    underline: u8,
+    reverse: bool,
+    width: u32,
+    height: u32,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            bold: false,
+            underline: 0,
+            reverse: false,
+            width: 1,
+            height: 1,
+        }
+    }
+}
+
+/// Interprets an ESC/POS byte stream and renders it onto a virtual paper roll
+pub struct Emulator {
+    /// Printed rows so far, PRINTER_DOTS_PER_LINE wide
+    roll: Vec<u8>,
+    /// Pieces of the line currently being assembled
+    line: Vec<GrayImage>,
+    line_width: u32,
+    line_spacing: u32,
+    align: Align,
+    style: Style,
+}
+
+impl Emulator {
+    pub fn new() -> Self {
+        Self {
+            roll: Vec::new(),
+            line: Vec::new(),
+            line_width: 0,
+            line_spacing: DEFAULT_LINE_SPACING,
+            align: Align::Left,
+            style: Style::default(),
+        }
+    }
+
+    /// Carry on from paper printed earlier
+    pub fn with_roll(roll: GrayImage) -> Self {
+        assert_eq!(roll.width(), PRINTER_DOTS_PER_LINE);
+        Self {
+            roll: roll.into_raw(),
+            ..Self::new()
+        }
+    }
+
+    /// Tear off all but the last `rows` of paper
+    pub fn trim(&mut self, rows: u32) {
+        let keep = rows as usize * PRINTER_DOTS_PER_LINE as usize;
+        if self.roll.len() > keep {
+            self.roll.drain(..self.roll.len() - keep);
+        }
+    }
+
+    /// The paper printed so far
+    pub fn image(&self) -> GrayImage {
+        let height = self.roll.len() as u32 / PRINTER_DOTS_PER_LINE;
+        GrayImage::from_raw(PRINTER_DOTS_PER_LINE, height, self.roll.clone())
+            .expect("Roll is a whole number of rows")
+    }
+
+    /// Interpret as many complete commands from `data` as possible, returning how many bytes were consumed
+    pub fn interpret(&mut self, data: &[u8]) -> usize {
+        let mut idx = 0;
+        while idx < data.len() {
+            match self.step(&data[idx..]) {
+                Some(n) => idx += n,
+                // Incomplete command, wait for the rest
+                None => break,
+            }
+        }
+        idx
+    }
+
+    /// Interpret a single command or character, returning its length
+    fn step(&mut self, data: &[u8]) -> Option<usize> {
+        let arg = |n: usize| data.get(n).copied();
+        // Commands are only consumed once all of their bytes have arrived
+        let complete = |len: usize| (data.len() >= len).then_some(len);
+        match data[0] {
+            LF => {
+                self.print_line();
+                Some(1)
+            }
+            CR => Some(1),
+            ESC => match arg(1)? {
+                b'@' => {
+                    self.reset();
+                    Some(2)
+                }
+                b'a' => {
+                    self.align = match arg(2)? {
+                        1 | b'1' => Align::Center,
+                        2 | b'2' => Align::Right,
+                        _ => Align::Left,
+                    };
+                    Some(3)
+                }
+                b'E' | b'G' => {
+                    self.style.bold = arg(2)? & 1 != 0;
+                    Some(3)
+                }
+                b'-' => {
+                    self.style.underline = (arg(2)? % b'0').min(2);
+                    Some(3)
+                }
+                b'!' => {
+                    let n = arg(2)?;
+                    self.style.bold = n & 0x08 != 0;
+                    self.style.height = if n & 0x10 != 0 { 2 } else { 1 };
+                    self.style.width = if n & 0x20 != 0 { 2 } else { 1 };
+                    self.style.underline = if n & 0x80 != 0 { 1 } else { 0 };
+                    Some(3)
+                }
+                b'd' => {
+                    for _ in 0..arg(2)?.max(1) {
+                        self.print_line();
+                    }
+                    Some(3)
+                }
+                b'J' => {
+                    self.print_line_with_feed(arg(2)? as u32);
+                    Some(3)
+                }
+                b'2' => {
+                    self.line_spacing = DEFAULT_LINE_SPACING;
+                    Some(2)
+                }
+                b'3' => {
+                    self.line_spacing = arg(2)? as u32;
+                    Some(3)
+                }
+                b'*' => {
+                    let mode = arg(2)?;
+                    let columns = arg(3)? as usize + arg(4)? as usize * 256;
+                    let (bytes_per_column, double_width) = match mode {
+                        0 => (1, true),
+                        1 => (1, false),
+                        32 => (3, true),
+                        _ => (3, false),
+                    };
+                    let len = 5 + columns * bytes_per_column;
+                    let bits = data.get(5..len)?;
+                    self.push_bit_image(bits, columns, bytes_per_column, double_width);
+                    Some(len)
+                }
+                b'p' => complete(5),
+                b'$' => complete(4),
+                b'i' | b'm' => {
+                    self.cut();
+                    Some(2)
+                }
+                // Single argument commands we don't render: code page, font, character set, etc.
+                _ => complete(3),
+            },
+            GS => match arg(1)? {
+                b'!' => {
+                    let n = arg(2)?;
+                    self.style.width = ((n >> 4) as u32 + 1).min(8);
+                    self.style.height = ((n & 0x0F) as u32 + 1).min(8);
+                    Some(3)
+                }
+                b'B' => {
+                    self.style.reverse = arg(2)? & 1 != 0;
+                    Some(3)
+                }
+                b'V' => {
+                    let len = match arg(2)? {
+                        65 | 66 => complete(4)?,
+                        _ => 3,
+                    };
+                    self.cut();
+                    Some(len)
+                }
+                b'v' => {
+                    // GS v 0 m xL xH yL yH d1...dk
+                    let mode = arg(3)?;
+                    let width_bytes = arg(4)? as usize + arg(5)? as usize * 256;
+                    let height = arg(6)? as usize + arg(7)? as usize * 256;
+                    let len = 8 + width_bytes * height;
+                    let bits = data.get(8..len)?;
+                    self.print_raster(bits, width_bytes, height, mode);
+                    Some(len)
+                }
+                b'k' => {
+                    // Barcodes: NUL terminated for m <= 6, length prefixed otherwise
+                    let m = arg(2)?;
+                    if m <= 6 {
+                        let end = data[3..].iter().position(|&b| b == 0)?;
+                        Some(3 + end + 1)
+                    } else {
+                        complete(4 + arg(3)? as usize)
+                    }
+                }
+                b'(' => complete(5 + arg(3)? as usize + arg(4)? as usize * 256),
+                b'L' | b'W' => complete(4),
+                _ => complete(3),
+            },
+            DLE => complete(3),
+            FS => complete(2),
+            b if b < 0x20 => Some(1),
+            b => {
+                self.push_char(b);
+                Some(1)
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.line.clear();
+        self.line_width = 0;
+        self.line_spacing = DEFAULT_LINE_SPACING;
+        self.align = Align::Left;
+        self.style = Style::default();
+    }
+
+    /// Add a piece to the current line, wrapping if it doesn't fit
+    fn push_piece(&mut self, piece: GrayImage) {
+        if self.line_width + piece.width() > PRINTER_DOTS_PER_LINE && !self.line.is_empty() {
+            self.print_line();
+        }
+        self.line_width += piece.width();
+        self.line.push(piece);
+    }
+
+    fn push_char(&mut self, c: u8) {
+        let Style {
+            bold,
+            underline,
+            reverse,
+            width,
+            height,
+        } = self.style;
+
+        let is_set = |x: u32, y: u32| font_dot(c, x, y);
+
+        let mut glyph = GrayImage::from_pixel(GLYPH_WIDTH * width, GLYPH_HEIGHT * height, WHITE);
+        for (x, y, px) in glyph.enumerate_pixels_mut() {
+            let (gx, gy) = (x / width, y / height);
+            let mut black = is_set(gx, gy) || (bold && gx > 0 && is_set(gx - 1, gy));
+            if underline > 0 && gy >= GLYPH_HEIGHT - underline as u32 {
+                black = true;
+            }
+            if black != reverse {
+                *px = BLACK;
+            }
+        }
+        self.push_piece(glyph);
+    }
+
+    fn push_bit_image(&mut self, bits: &[u8], columns: usize, bytes_per_column: usize, double_width: bool) {
+        let scale = if double_width { 2 } else { 1 };
+        let mut piece = GrayImage::from_pixel(
+            (columns * scale) as u32,
+            (bytes_per_column * 8) as u32,
+            WHITE,
+        );
+        for (column, chunk) in bits.chunks(bytes_per_column).enumerate() {
+            for (byte_idx, byte) in chunk.iter().enumerate() {
+                for bit in 0..8 {
+                    if byte & (0x80 >> bit) != 0 {
+                        let y = (byte_idx * 8 + bit) as u32;
+                        for dx in 0..scale {
+                            piece.put_pixel((column * scale + dx) as u32, y, BLACK);
+                        }
+                    }
+                }
+            }
+        }
+        self.push_piece(piece);
+    }
+
+    fn print_raster(&mut self, bits: &[u8], width_bytes: usize, height: usize, mode: u8) {
+        let (sx, sy) = match mode % b'0' {
+            1 => (2, 1),
+            2 => (1, 2),
+            3 => (2, 2),
+            _ => (1, 1),
+        };
+        let width = (width_bytes * 8) as u32;
+        let mut piece = GrayImage::from_pixel(width * sx, height as u32 * sy, WHITE);
+        for (x, y, px) in piece.enumerate_pixels_mut() {
+            let (bx, by) = ((x / sx) as usize, (y / sy) as usize);
+            if bits[by * width_bytes + bx / 8] & (0x80 >> (bx % 8)) != 0 {
+                *px = BLACK;
+            }
+        }
+
+        // Raster images are printed immediately rather than waiting for a line feed
+        self.flush_line();
+        self.push_piece(piece);
+        self.print_line_with_feed(0);
+    }
+
+    /// Print pending content, if any, without feeding
+    fn flush_line(&mut self) {
+        if !self.line.is_empty() {
+            self.print_line_with_feed(0);
+        }
+    }
+
+    /// Print the current line and feed by the line spacing
+    fn print_line(&mut self) {
+        let spacing = self.line_spacing;
+        self.print_line_with_feed(spacing);
+    }
+
+    /// Print the current line, advancing by at least `feed` dots
+    fn print_line_with_feed(&mut self, feed: u32) {
+        let height = self.line.iter().map(|p| p.height()).max().unwrap_or(0);
+        let advance = height.max(feed);
+
+        let top = self.roll.len();
+        let width = PRINTER_DOTS_PER_LINE as usize;
+        self.roll.resize(top + advance as usize * width, WHITE.0[0]);
+
+        let mut x = match self.align {
+            Align::Left => 0,
+            Align::Center => PRINTER_DOTS_PER_LINE.saturating_sub(self.line_width) / 2,
+            Align::Right => PRINTER_DOTS_PER_LINE.saturating_sub(self.line_width),
+        };
+        for piece in self.line.drain(..) {
+            // Pieces sit on a common baseline at the bottom of the line
+            let y_offset = height - piece.height();
+            for (px, py, p) in piece.enumerate_pixels() {
+                if x + px < PRINTER_DOTS_PER_LINE {
+                    let idx = top + (y_offset + py) as usize * width + (x + px) as usize;
+                    self.roll[idx] = p.0[0];
+                }
+            }
+            x += piece.width();
+        }
+        self.line_width = 0;
+    }
+
+    /// Draw a dashed line where the paper would be cut
+    fn cut(&mut self) {
+        self.flush_line();
+        let top = self.roll.len();
+        self.roll.resize(top + PRINTER_DOTS_PER_LINE as usize * 3, WHITE.0[0]);
+        for x in (0..PRINTER_DOTS_PER_LINE as usize).filter(|x| x % 8 < 4) {
+            self.roll[top + PRINTER_DOTS_PER_LINE as usize + x] = BLACK.0[0];
+        }
+    }
+}
+
+/// Backend rendering everything it receives to a PNG instead of a real printer
+pub struct EmulatorBackend {
+    path: PathBuf,
+    emulator: Emulator,
+    pending: Vec<u8>,
+}
+
+impl EmulatorBackend {
+    /// Print onto the end of the roll in `path`, if there is one
+    pub fn new(path: PathBuf) -> Self {
+        let emulator = match image::open(&path).map(|roll| roll.to_luma8()) {
+            Ok(roll) if roll.width() == PRINTER_DOTS_PER_LINE => Emulator::with_roll(roll),
+            _ => Emulator::new(),
+        };
+        Self {
+            path,
+            emulator,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Write for EmulatorBackend {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let consumed = self.emulator.interpret(&self.pending);
+        self.pending.drain(..consumed);
+        self.emulator.trim(MAX_ROLL_ROWS);
+        self.emulator
+            .image()
+            .save(&self.path)
+            .map_err(io::Error::other)
+    }
+}
+
+impl PrinterBackend for EmulatorBackend {
+    fn describe(&self) -> String {
+        format!("emulated paper roll {}", self.path.display())
+    }
+
+    fn status(&mut self) -> Result<PrinterStatus> {
+        Ok(PrinterStatus::Ready)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn black_columns(image: &GrayImage) -> Vec<u32> {
+        (0..image.width())
+            .filter(|&x| (0..image.height()).any(|y| image.get_pixel(x, y) == &BLACK))
+            .collect()
+    }
+
+    #[test]
+    fn test_emulator_text() {
+        let mut emulator = Emulator::new();
+        let data = [&[ESC, b'@', ESC, b'a', 1][..], b"Hi\n"].concat();
+        assert_eq!(emulator.interpret(&data), data.len());
+
+        let image = emulator.image();
+        assert_eq!(image.height(), DEFAULT_LINE_SPACING);
+        let columns = black_columns(&image);
+        assert!(columns.iter().all(|x| (180..204).contains(x)));
+
+        // Incomplete commands are left for later
+        assert_eq!(emulator.interpret(&[b'A', ESC, b'*', 33, 2]), 1);
+    }
+
+    #[test]
+    fn test_emulator_bit_image() {
+        let mut emulator = Emulator::new();
+        let data = [ESC, b'3', 0, ESC, b'*', 33, 1, 0, 0xFF, 0x00, 0x01, LF];
+        assert_eq!(emulator.interpret(&data), data.len());
+
+        let image = emulator.image();
+        assert_eq!(image.height(), 24);
+        assert_eq!(image.get_pixel(0, 0), &BLACK);
+        assert_eq!(image.get_pixel(0, 8), &WHITE);
+        assert_eq!(image.get_pixel(0, 23), &BLACK);
+        assert_eq!(black_columns(&image), vec![0]);
+    }
+
+    #[test]
+    fn test_emulator_roll() {
+        let mut emulator = Emulator::new();
+        emulator.interpret(b"Hi\n\n\n");
+        let roll = emulator.image();
+
+        // Printing carries on from an earlier roll, which only keeps its end
+        let mut emulator = Emulator::with_roll(roll.clone());
+        emulator.interpret(b"\n");
+        assert_eq!(
+            emulator.image().height(),
+            roll.height() + DEFAULT_LINE_SPACING
+        );
+        emulator.trim(DEFAULT_LINE_SPACING * 2);
+        let image = emulator.image();
+        assert_eq!(image.height(), DEFAULT_LINE_SPACING * 2);
+        assert!(black_columns(&image).is_empty());
+    }
+
+    #[test]
+    fn test_emulator_partial_commands() {
+        let commands: [&[u8]; 11] = [
+            &[DLE, 4, 1],
+            &[FS, b'.'],
+            &[ESC, b'p', 0, 25, 250],
+            &[ESC, b'$', 0, 0],
+            &[ESC, b't', 16],
+            &[GS, b'k', 73, 3, b'{', b'B', b'1'],
+            &[GS, b'k', 2, b'1', b'2', 0],
+            &[GS, b'(', b'k', 3, 0, 49, 67, 4],
+            &[GS, b'L', 0, 0],
+            &[GS, b'V', 66, 0],
+            &[GS, b'H', 0],
+        ];
+        for command in commands {
+            // Nothing is consumed until the whole command has arrived
+            let mut emulator = Emulator::new();
+            let mut pending = Vec::new();
+            for (i, &byte) in command.iter().enumerate() {
+                pending.push(byte);
+                let consumed = emulator.interpret(&pending);
+                assert!(consumed <= pending.len(), "{:?}", command);
+                pending.drain(..consumed);
+                assert_eq!(pending.is_empty(), i == command.len() - 1, "{:?}", command);
+            }
+        }
+    }
+}
@@ -0,0 +1,14 @@
+//! ESC/POS control bytes shared by the printer thread and the emulator
+
+pub const LF: u8 = 0x0A;
+pub const CR: u8 = 0x0D;
+pub const DLE: u8 = 0x10;
+pub const ESC: u8 = 0x1B;
+pub const FS: u8 = 0x1C;
+pub const GS: u8 = 0x1D;
+pub const EOT: u8 = 0x04;
+
+/// DLE EOT real-time status requests
+pub const STATUS_PRINTER: u8 = 1;
+pub const STATUS_OFFLINE_CAUSE: u8 = 2;
+pub const STATUS_ROLL_PAPER: u8 = 4;
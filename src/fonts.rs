@@ -0,0 +1,435 @@
+//! Text none of the printer's code pages can encode is drawn with TrueType fonts and sent as a
+//! raster image.
+//! Drawn lines use the same character grid as the printer's own 12x24 font, so they line up
+//! with the rest of the layout. Lua scripts can also draw text at any size with `draw_text`.
+
+use anyhow::{bail, ensure, format_err, Context, Result};
+use image::{GrayImage, Luma};
+use rusttype::{point, Font, PositionedGlyph, Scale};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::codepage::{self, CodePage};
+use crate::emulator;
+use crate::escpos::GS;
+use crate::imaging::MAX_LENGTH;
+use crate::layout::{char_columns, Line};
+use crate::markup;
+use crate::printer::{PRINTER_CHARS_PER_LINE, PRINTER_DOTS_PER_LINE, PRINTER_LINE_DOTS};
+
+/// Covers most European scripts, and some symbols and emoji
+const BUNDLED_FONT: &[u8] = include_bytes!("../assets/DejaVuSans.ttf");
+/// Monochrome Noto Emoji, for the emoji DejaVu Sans lacks
+const EMOJI_FONT: &[u8] = include_bytes!("../assets/NotoEmoji-Regular.ttf");
+
+const CELL_WIDTH: u32 = PRINTER_DOTS_PER_LINE / PRINTER_CHARS_PER_LINE as u32;
+const GLYPH_HEIGHT: u32 = 24;
+/// Distance from the top of a glyph to the baseline
+const BASELINE: f32 = 19.;
+const UNDERLINE: u32 = 22;
+
+const WHITE: Luma<u8> = Luma([0xFF]);
+const BLACK: Luma<u8> = Luma([0x00]);
+
+/// Largest text `draw_text` draws, in dots per line
+pub const MAX_TEXT_SIZE: u32 = PRINTER_DOTS_PER_LINE;
+
+/// Typefaces for `draw_text`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Face {
+    /// The printer's own bitmap font, scaled up by whole numbers. ASCII only.
+    Mono,
+    /// The bundled TrueType font, falling back to any extra fonts
+    Sans,
+}
+
+impl FromStr for Face {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mono" => Ok(Self::Mono),
+            "sans" => Ok(Self::Sans),
+            _ => bail!("Unknown font {:?}, try mono or sans", s),
+        }
+    }
+}
+
+/// Fonts to print with: the printer's own, then TrueType fonts in order of preference
+pub struct Fonts {
+    code_pages: Vec<CodePage>,
+    fonts: Vec<Font<'static>>,
+}
+
+impl Fonts {
+    /// The printer's font with the given code pages, then the bundled font, then an emoji font,
+    /// the bundled one unless another is given, falling back to any extra fonts given
+    pub fn load(code_pages: &[CodePage], emoji: Option<&Path>, extra: &[PathBuf]) -> Result<Self> {
+        let mut fonts = vec![Font::try_from_bytes(BUNDLED_FONT).context("Bundled font is broken")?];
+        fonts.push(match emoji {
+            Some(path) => load_font(path)?,
+            None => Font::try_from_bytes(EMOJI_FONT).context("Bundled emoji font is broken")?,
+        });
+        for path in extra {
+            fonts.push(load_font(path)?);
+        }
+        Ok(Self {
+            code_pages: code_pages.to_vec(),
+            fonts,
+        })
+    }
+
+    /// ESC/POS for laid out lines. Lines the printer can print are sent as text, the rest are
+    /// drawn.
+    pub fn escpos(&self, lines: &[Line]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut text = Vec::new();
+        for line in lines {
+            match codepage::encode(line, &self.code_pages) {
+                Some(encoded) => text.push(encoded),
+                None => {
+                    out.extend(markup::to_escpos(&std::mem::take(&mut text)));
+                    out.extend(raster(&self.draw_line(line)));
+                }
+            }
+        }
+        out.extend(markup::to_escpos(&text));
+        out
+    }
+
+    /// Draw text in black on white, `size` dots per line, as wide as the longest line. Fails if
+    /// either side would be longer than the printer prints images.
+    pub fn draw_text(&self, text: &str, face: Face, size: u32) -> Result<GrayImage> {
+        ensure!(
+            (8..=MAX_TEXT_SIZE).contains(&size),
+            "Text size should be from 8 to {}",
+            MAX_TEXT_SIZE
+        );
+        match face {
+            Face::Mono => draw_mono(text, size),
+            Face::Sans => self.draw_sans(text, size),
+        }
+    }
+
+    fn draw_sans(&self, text: &str, size: u32) -> Result<GrayImage> {
+        // Scale so a line, gaps included, is exactly `size` dots
+        let metrics = self.fonts[0].v_metrics(Scale::uniform(1.));
+        let scale =
+            Scale::uniform(size as f32 / (metrics.ascent - metrics.descent + metrics.line_gap));
+        let ascent = self.fonts[0].v_metrics(scale).ascent;
+
+        let mut glyphs: Vec<PositionedGlyph> = Vec::new();
+        let mut width = 0.;
+        let rows: Vec<&str> = text.split('\n').collect();
+        for (row, line) in rows.iter().enumerate() {
+            let mut x = 0.;
+            let mut last = None;
+            for c in line.chars() {
+                let (font, glyph) = self
+                    .fonts
+                    .iter()
+                    .map(|f| (f, f.glyph(c)))
+                    .find(|(_, g)| g.id().0 != 0)
+                    .unwrap_or_else(|| (&self.fonts[0], self.fonts[0].glyph(c)));
+                let glyph = glyph.scaled(scale);
+                if let Some((last_font, last_id)) = last {
+                    if std::ptr::eq(last_font, font) {
+                        x += font.pair_kerning(scale, last_id, glyph.id());
+                    }
+                }
+                last = Some((font, glyph.id()));
+                let advance = glyph.h_metrics().advance_width;
+                let top = (row as u32 * size) as f32;
+                glyphs.push(glyph.positioned(point(x, top + ascent)));
+                x += advance;
+            }
+            width = x.max(width);
+        }
+
+        let (width, height) = (width.ceil() as u32, rows.len() as u32 * size);
+        ensure!(
+            width <= MAX_LENGTH && height <= MAX_LENGTH,
+            "Text too long, it can be at most {} dots either way",
+            MAX_LENGTH
+        );
+        let mut image = GrayImage::from_pixel(width.max(1), height, WHITE);
+        for glyph in glyphs {
+            if let Some(bounds) = glyph.pixel_bounding_box() {
+                glyph.draw(|gx, gy, coverage| {
+                    let (px, py) = (bounds.min.x + gx as i32, bounds.min.y + gy as i32);
+                    let inside = px >= 0
+                        && py >= 0
+                        && (px as u32) < image.width()
+                        && (py as u32) < image.height();
+                    if coverage > 0.5 && inside {
+                        image.put_pixel(px as u32, py as u32, BLACK);
+                    }
+                });
+            }
+        }
+        Ok(image)
+    }
+
+    /// Draw a line, taking up as much paper as it would have as text
+    fn draw_line(&self, line: &Line) -> GrayImage {
+        let tall = line.iter().any(|s| s.style.double_height);
+        let height = PRINTER_LINE_DOTS * if tall { 2 } else { 1 };
+        let mut image = GrayImage::from_pixel(PRINTER_DOTS_PER_LINE, height, WHITE);
+
+        let mut x = 0;
+        for span in line {
+            let sx = if span.style.double_width { 2 } else { 1 };
+            let sy = if span.style.double_height { 2 } else { 1 };
+            for c in span.text.chars() {
+                let width = CELL_WIDTH * char_columns(c) as u32 * sx;
+                // Like the printer, normal height characters sit at the bottom of a tall line
+                let top = height - PRINTER_LINE_DOTS * sy;
+                let cell = Cell {
+                    x,
+                    top,
+                    width,
+                    height: GLYPH_HEIGHT * sy,
+                };
+                self.draw_char(&mut image, c, &cell);
+
+                if span.style.bold {
+                    // Smear sideways to thicken the strokes
+                    for px in (cell.x..cell.right(&image)).rev().skip(1) {
+                        for py in cell.top..cell.bottom(&image) {
+                            if image.get_pixel(px, py) == &BLACK {
+                                image.put_pixel(px + 1, py, BLACK);
+                            }
+                        }
+                    }
+                }
+                if span.style.underline {
+                    for px in cell.x..cell.right(&image) {
+                        image.put_pixel(px, (cell.top + UNDERLINE * sy).min(height - 1), BLACK);
+                    }
+                }
+                if span.style.reverse {
+                    for px in cell.x..cell.right(&image) {
+                        for py in cell.top..cell.bottom(&image) {
+                            let Luma([v]) = *image.get_pixel(px, py);
+                            image.put_pixel(px, py, Luma([0xFF - v]));
+                        }
+                    }
+                }
+                x += width;
+            }
+        }
+        image
+    }
+
+    /// Draw a character from the first font that has it, or a box if none do
+    fn draw_char(&self, image: &mut GrayImage, c: char, cell: &Cell) {
+        if c == ' ' {
+            return;
+        }
+
+        let glyph = match self.fonts.iter().map(|f| f.glyph(c)).find(|g| g.id().0 != 0) {
+            Some(glyph) => glyph,
+            None => {
+                for px in cell.x + 1..cell.right(image).saturating_sub(1) {
+                    for py in cell.top + 2..cell.bottom(image).saturating_sub(2) {
+                        let edge = px == cell.x + 1
+                            || px + 2 == cell.right(image)
+                            || py == cell.top + 2
+                            || py + 3 == cell.bottom(image);
+                        if edge {
+                            image.put_pixel(px, py, BLACK);
+                        }
+                    }
+                }
+                return;
+            }
+        };
+
+        // Squash wide glyphs horizontally so they fit their cell
+        let size = cell.height as f32;
+        let advance = glyph
+            .clone()
+            .scaled(Scale::uniform(size))
+            .h_metrics()
+            .advance_width;
+        let squash = (cell.width as f32 / advance.max(1.)).min(1.);
+        let scaled = glyph.scaled(Scale {
+            x: size * squash,
+            y: size,
+        });
+        let offset = (cell.width as f32 - advance * squash) / 2.;
+        let baseline = cell.top as f32 + BASELINE * size / GLYPH_HEIGHT as f32;
+        let positioned = scaled.positioned(point(cell.x as f32 + offset, baseline));
+
+        if let Some(bounds) = positioned.pixel_bounding_box() {
+            positioned.draw(|gx, gy, coverage| {
+                let (px, py) = (bounds.min.x + gx as i32, bounds.min.y + gy as i32);
+                let inside = px >= cell.x as i32
+                    && py >= 0
+                    && (px as u32) < cell.right(image)
+                    && (py as u32) < image.height();
+                if coverage > 0.5 && inside {
+                    image.put_pixel(px as u32, py as u32, BLACK);
+                }
+            });
+        }
+    }
+}
+
+/// Read a TrueType font from disk
+fn load_font(path: &Path) -> Result<Font<'static>> {
+    let data = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    Font::try_from_vec(data).ok_or_else(|| format_err!("{:?} is not a TrueType font", path))
+}
+
+/// Text in the printer's font, scaled up as far as fits in `size` dots per line. Lines are never
+/// shorter than the font itself.
+fn draw_mono(text: &str, size: u32) -> Result<GrayImage> {
+    let scale = (size / emulator::GLYPH_HEIGHT).max(1);
+    let cell_width = emulator::GLYPH_WIDTH * scale;
+    let cell_height = emulator::GLYPH_HEIGHT * scale;
+    let line_height = size.max(cell_height);
+    let rows: Vec<&str> = text.split('\n').collect();
+    let columns = rows.iter().map(|r| r.chars().count()).max().unwrap_or(0) as u32;
+
+    let (width, height) = (columns * cell_width, rows.len() as u32 * line_height);
+    ensure!(
+        width <= MAX_LENGTH && height <= MAX_LENGTH,
+        "Text too long, it can be at most {} dots either way",
+        MAX_LENGTH
+    );
+    let mut image = GrayImage::from_pixel(width.max(1), height, WHITE);
+    for (row, line) in rows.iter().enumerate() {
+        for (column, c) in line.chars().enumerate() {
+            let c = match c.is_ascii() {
+                true => c as u8,
+                false => b'?',
+            };
+            let (left, top) = (column as u32 * cell_width, row as u32 * line_height);
+            for y in 0..cell_height {
+                for x in 0..cell_width {
+                    if emulator::font_dot(c, x / scale, y / scale) {
+                        image.put_pixel(left + x, top + y, BLACK);
+                    }
+                }
+            }
+        }
+    }
+    Ok(image)
+}
+
+/// Area a character is drawn in
+struct Cell {
+    x: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Cell {
+    fn right(&self, image: &GrayImage) -> u32 {
+        (self.x + self.width).min(image.width())
+    }
+
+    fn bottom(&self, image: &GrayImage) -> u32 {
+        (self.top + self.height).min(image.height())
+    }
+}
+
+/// GS v 0 raster image, printed exactly as tall as it is
+pub fn raster(image: &GrayImage) -> Vec<u8> {
+    let width_bytes = (image.width() as usize).div_ceil(8);
+    let height = image.height() as usize;
+    let mut out = vec![
+        GS,
+        b'v',
+        b'0',
+        0,
+        width_bytes as u8,
+        (width_bytes >> 8) as u8,
+        height as u8,
+        (height >> 8) as u8,
+    ];
+    let mut bits = vec![0u8; width_bytes * height];
+    for (x, y, Luma([v])) in image.enumerate_pixels() {
+        if *v < 0x80 {
+            bits[y as usize * width_bytes + x as usize / 8] |= 0x80 >> (x % 8);
+        }
+    }
+    out.extend(bits);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::{layout, Align, Paragraph};
+    use crate::markup::{Span, Style};
+
+    fn lines(text: &str) -> Vec<Line> {
+        let paragraph = Paragraph {
+            align: Align::Left,
+            spans: vec![Span {
+                text: text.into(),
+                style: Style::default(),
+            }],
+        };
+        layout(&[paragraph], PRINTER_CHARS_PER_LINE)
+    }
+
+    #[test]
+    fn test_fallback() {
+        let fonts = Fonts::load(&[CodePage::Cp437], None, &[]).unwrap();
+        let drawn = |bytes: &[u8]| bytes.windows(2).any(|w| w == [GS, b'v']);
+
+        // Anything the code pages have is left to the printer
+        assert!(!drawn(&fonts.escpos(&lines("hello"))));
+        assert!(!drawn(&fonts.escpos(&lines("h\u{e9}llo"))));
+        let ascii_only = Fonts::load(&[], None, &[]).unwrap();
+        assert!(drawn(&ascii_only.escpos(&lines("h\u{e9}llo"))));
+
+        // Anything else is drawn, a full line at a time
+        let unicode = fonts.escpos(&lines("h\u{e9}llo \u{65e5}"));
+        let header = [GS, b'v', b'0', 0, 48, 0, PRINTER_LINE_DOTS as u8, 0];
+        let start = unicode.windows(8).position(|w| w == header).unwrap();
+        assert_eq!(unicode.len() - start, 8 + 48 * PRINTER_LINE_DOTS as usize + 15);
+
+        // Emoji DejaVu Sans lacks come from the bundled emoji font
+        let pizza = '\u{1f355}';
+        assert_eq!(ascii_only.fonts[0].glyph(pizza).id().0, 0);
+        assert_ne!(ascii_only.fonts[1].glyph(pizza).id().0, 0);
+
+        // The accent is drawn in the second cell, above where an e would be
+        let image = ascii_only.draw_line(&lines("h\u{e9}")[0]);
+        let inked = |x0: u32, x1: u32| (x0..x1).any(|x| (0..8).any(|y| image.get_pixel(x, y) == &BLACK));
+        assert!(inked(CELL_WIDTH, 2 * CELL_WIDTH));
+    }
+
+    #[test]
+    fn test_draw_text() {
+        let fonts = Fonts::load(&[], None, &[]).unwrap();
+        let ink = |image: &GrayImage| image.pixels().filter(|&&p| p == BLACK).count();
+
+        // Lines are exactly the size asked for
+        let sans = fonts.draw_text("Hi\nthere", Face::Sans, 100).unwrap();
+        assert_eq!(sans.height(), 200);
+        assert!(sans.width() > 200 && sans.width() < 400);
+        assert!(ink(&sans) > 0);
+
+        // The printer's font only grows in whole steps
+        let mono = fonts.draw_text("Hi", Face::Mono, 60).unwrap();
+        assert_eq!(mono.dimensions(), (48, 60));
+        let dots = |c| {
+            (0..emulator::GLYPH_HEIGHT)
+                .flat_map(|y| (0..emulator::GLYPH_WIDTH).map(move |x| (x, y)))
+                .filter(|&(x, y)| emulator::font_dot(c, x, y))
+                .count()
+        };
+        assert_eq!(ink(&mono), (dots(b'H') + dots(b'i')) * 4);
+
+        assert_eq!(fonts.draw_text("x", Face::Mono, 8).unwrap().height(), 24);
+        assert!(fonts.draw_text("x", Face::Sans, 4).is_err());
+        assert!(fonts.draw_text(&"x".repeat(1000), Face::Mono, 96).is_err());
+        assert_eq!("mono".parse::<Face>().unwrap(), Face::Mono);
+        assert!("comic".parse::<Face>().is_err());
+    }
+}
@@ -0,0 +1,866 @@
+//! Turning pictures into black and white dots: scaling, tone adjustments and dithering, tuneable
+//! per image with `!print --dither atkinson --contrast 20 ...` or an options table in Lua.
+//! `--enhance` adds automatic levels and sharpening, which thermal paper needs to keep photos
+//! from turning to mud, and prints screenshots and line art without dithering. Animated GIFs can
+//! be printed as a flipbook of frames with `--frames`.
+
+use anyhow::{bail, ensure, format_err, Context, Result};
+use image::codecs::gif::GifDecoder;
+use image::imageops::FilterType;
+use image::{
+    imageops, AnimationDecoder, DynamicImage, GenericImageView, GrayImage, ImageDecoder,
+    ImageFormat, Luma, Rgb, RgbImage,
+};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::str::FromStr;
+
+use crate::emulator::{font_dot, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::printer::{PRINTER_DOTS_PER_LINE, PRINTER_DOTS_PER_MM};
+
+/// Longest an image may print, in dots. Longer images are scaled down to fit.
+pub const MAX_LENGTH: u32 = 400 * PRINTER_DOTS_PER_MM;
+
+/// Fraction of the darkest and lightest pixels clipped by auto levels
+const LEVELS_CLIP: f32 = 0.01;
+/// How much histogram equalization is mixed into photos. Fully equalized photos look harsh.
+const EQUALIZE_AMOUNT: f32 = 0.5;
+/// Unsharp mask radius and strength, for local contrast
+const UNSHARP_SIGMA: f32 = 3.;
+const UNSHARP_AMOUNT: f32 = 0.6;
+/// Strength of the Laplacian edge sharpening done last
+const EDGE_AMOUNT: f32 = 0.4;
+/// Images where this many colours cover nearly all of the picture are treated as line art
+const LINE_ART_COLORS: usize = 8;
+const LINE_ART_COVERAGE: f32 = 0.95;
+/// Pixels looked at when deciding whether an image is line art
+const LINE_ART_SAMPLES: usize = 100_000;
+
+/// Most frames in a flipbook, and columns in its grid
+const MAX_FRAMES: u32 = 24;
+const MAX_COLUMNS: u32 = 4;
+/// Frames of an animation looked at. Longer animations are cut short.
+const MAX_SCANNED_FRAMES: usize = 1000;
+/// Largest GIF made into a flipbook, in pixels. Every frame is decoded to this size, however
+/// little of it the frame draws.
+const MAX_FLIPBOOK_PIXELS: u64 = 1024 * 1024;
+/// Pixels decoded while looking through an animation, after which the rest is cut short
+const MAX_DECODED_PIXELS: u64 = 256 * 1024 * 1024;
+/// Space between flipbook frames, in dots
+const FRAME_GAP: u32 = 8;
+
+/// How grey is turned into black and white
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dither {
+    /// Floyd-Steinberg error diffusion
+    Floyd,
+    /// Atkinson error diffusion, which loses some of the error for more contrast
+    Atkinson,
+    /// Stucki error diffusion, spreading error further for smoother gradients
+    Stucki,
+    /// Ordered dithering with an 8x8 Bayer matrix
+    Bayer,
+    /// Plain thresholding, best for line art and text
+    Threshold,
+}
+
+impl Dither {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Dither::Floyd => "floyd",
+            Dither::Atkinson => "atkinson",
+            Dither::Stucki => "stucki",
+            Dither::Bayer => "bayer",
+            Dither::Threshold => "threshold",
+        }
+    }
+}
+
+impl FromStr for Dither {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "floyd" | "floyd-steinberg" => Ok(Dither::Floyd),
+            "atkinson" => Ok(Dither::Atkinson),
+            "stucki" => Ok(Dither::Stucki),
+            "bayer" | "ordered" => Ok(Dither::Bayer),
+            "threshold" | "none" => Ok(Dither::Threshold),
+            _ => Err(format_err!(
+                "Unknown dither {}, try floyd, atkinson, stucki, bayer or threshold",
+                s
+            )),
+        }
+    }
+}
+
+/// Turning images to make better use of the paper
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    None,
+    /// Turn a quarter clockwise if that prints the image larger, as it does for wide images
+    Auto,
+    /// Clockwise, in degrees
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+impl FromStr for Rotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "0" | "none" => Ok(Rotation::None),
+            "auto" => Ok(Rotation::Auto),
+            "90" | "-270" => Ok(Rotation::Cw90),
+            "180" | "-180" => Ok(Rotation::Cw180),
+            "270" | "-90" => Ok(Rotation::Cw270),
+            _ => Err(format_err!(
+                "Unknown rotation {}, try auto, 90, 180 or 270",
+                s
+            )),
+        }
+    }
+}
+
+/// Names of the options, all but `invert` and `enhance` taking a value
+const OPTIONS: [&str; 10] = [
+    "dither",
+    "contrast",
+    "brightness",
+    "gamma",
+    "invert",
+    "width",
+    "enhance",
+    "rotate",
+    "frames",
+    "columns",
+];
+
+/// How to print an image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageOptions {
+    /// Chosen automatically if not given
+    pub dither: Option<Dither>,
+    /// Multiplies the distance from mid grey
+    pub contrast: f32,
+    /// Added to every pixel, from -1 to 1
+    pub brightness: f32,
+    /// Above 1 lightens mid tones, below 1 darkens them
+    pub gamma: f32,
+    pub invert: bool,
+    /// Printed width in dots
+    pub width: u32,
+    /// Automatic levels and sharpening
+    pub enhance: bool,
+    pub rotate: Rotation,
+    /// Frames of an animation to print, evenly spaced. 1 prints a still.
+    pub frames: u32,
+    /// Flipbook frames side by side
+    pub columns: u32,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        Self {
+            dither: None,
+            contrast: 1.,
+            brightness: 0.,
+            gamma: 1.,
+            invert: false,
+            width: PRINTER_DOTS_PER_LINE,
+            enhance: false,
+            rotate: Rotation::None,
+            frames: 1,
+            columns: 1,
+        }
+    }
+}
+
+impl ImageOptions {
+    /// Parse `--name value` options from the start of a command, returning the rest of it.
+    /// Parsing stops at the first word which isn't an option, so text starting with `--` still
+    /// prints.
+    pub fn parse_args(mut text: &str) -> Result<(Self, &str)> {
+        let mut options = Self::default();
+        loop {
+            text = text.trim_start();
+            let word = text.split_whitespace().next().unwrap_or("");
+            let name = match word.strip_prefix("--") {
+                Some(name) if OPTIONS.contains(&name) => name,
+                _ => return Ok((options, text)),
+            };
+            text = &text[word.len()..];
+
+            let value = match name {
+                "invert" | "enhance" => None,
+                _ => {
+                    let value = text.split_whitespace().next();
+                    text = &text.trim_start()[value.map_or(0, str::len)..];
+                    value
+                }
+            };
+            options.set(name, value)?;
+        }
+    }
+
+    /// Set an option by name. Percentages may be written with or without the `%`.
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<()> {
+        let value = || value.ok_or_else(|| format_err!("--{} needs a value", name));
+        match name {
+            "dither" => self.dither = Some(value()?.parse()?),
+            "contrast" => {
+                let contrast = percent(value()?)?;
+                ensure!(contrast >= -1., "Contrast can't go below -100%");
+                self.contrast = 1. + contrast;
+            }
+            "brightness" => {
+                let brightness = percent(value()?)?;
+                ensure!(
+                    (-1. ..=1.).contains(&brightness),
+                    "Brightness goes from -100% to 100%"
+                );
+                self.brightness = brightness;
+            }
+            "gamma" => {
+                let gamma: f32 = value()?
+                    .parse()
+                    .map_err(|_| format_err!("Gamma should be a number, like 1.8"))?;
+                ensure!(gamma > 0. && gamma.is_finite(), "Gamma must be above 0");
+                self.gamma = gamma;
+            }
+            "invert" => self.invert = true,
+            "enhance" => self.enhance = true,
+            "rotate" => self.rotate = value()?.parse()?,
+            "frames" => self.frames = count(value()?, MAX_FRAMES)?,
+            "columns" => self.columns = count(value()?, MAX_COLUMNS)?,
+            "width" => {
+                let value = value()?;
+                let width = match value.strip_suffix('%') {
+                    Some(pct) => pct
+                        .parse::<f32>()
+                        .map(|p| (p / 100. * PRINTER_DOTS_PER_LINE as f32).round() as u32),
+                    None => value.parse::<f32>().map(|dots| dots as u32),
+                }
+                .map_err(|_| format_err!("Width should be in dots, or a percentage like 50%"))?;
+                ensure!(
+                    (1..=PRINTER_DOTS_PER_LINE).contains(&width),
+                    "Width goes up to {} dots (100%)",
+                    PRINTER_DOTS_PER_LINE
+                );
+                self.width = width;
+            }
+            _ => bail!(
+                "Unknown image option {}, expected one of {}",
+                name,
+                OPTIONS.join(", ")
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// `20`, `20%` or `-20%` as a fraction
+fn percent(value: &str) -> Result<f32> {
+    let pct: f32 = value
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format_err!("Expected a percentage, got {}", value))?;
+    ensure!(pct.is_finite(), "Expected a percentage, got {}", value);
+    Ok(pct / 100.)
+}
+
+/// A whole number from 1 to `max`
+fn count(value: &str, max: u32) -> Result<u32> {
+    match value.parse() {
+        Ok(n) if (1..=max).contains(&n) => Ok(n),
+        _ => Err(format_err!(
+            "Expected a number from 1 to {}, got {}",
+            max,
+            value
+        )),
+    }
+}
+
+/// Decode an image and prepare it for the printer. Animated GIFs become a flipbook if
+/// `options.frames` asks for more than one frame.
+pub fn load(data: &[u8], options: &ImageOptions) -> Result<RgbImage> {
+    if options.frames > 1 && image::guess_format(data).ok() == Some(ImageFormat::Gif) {
+        let frames = gif_frames(data, options.frames).context("GIF parse failed")?;
+        if frames.len() > 1 {
+            return Ok(flipbook(&frames, options));
+        }
+    }
+    let image = image::load_from_memory(data).context("Image parse failed")?;
+    Ok(prepare(&image, options))
+}
+
+/// Up to `count` evenly spaced frames of an animated GIF
+fn gif_frames(data: &[u8], count: u32) -> Result<Vec<DynamicImage>> {
+    let decoder = || GifDecoder::new(Cursor::new(data));
+    let (width, height) = decoder()?.dimensions();
+    let pixels = width as u64 * height as u64;
+    ensure!(
+        pixels <= MAX_FLIPBOOK_PIXELS,
+        "That GIF is too big to make a flipbook of, at {}x{}",
+        width,
+        height
+    );
+    let scanned = (MAX_DECODED_PIXELS / pixels.max(1)).min(MAX_SCANNED_FRAMES as u64);
+
+    let total = decoder()?
+        .into_frames()
+        .take(scanned as usize)
+        .take_while(|f| f.is_ok())
+        .count();
+
+    let mut wanted: Vec<usize> = (0..count as usize)
+        .map(|i| i * total / count as usize)
+        .collect();
+    wanted.dedup();
+    let last = wanted.last().copied().unwrap_or(0);
+
+    let mut frames = Vec::with_capacity(wanted.len());
+    for (idx, frame) in decoder()?.into_frames().enumerate().take(last + 1) {
+        let frame = frame?;
+        if wanted.contains(&idx) {
+            frames.push(DynamicImage::ImageRgba8(frame.into_buffer()));
+        }
+    }
+    Ok(frames)
+}
+
+/// Frames laid out in a grid, read left to right, each numbered above
+fn flipbook(frames: &[DynamicImage], options: &ImageOptions) -> RgbImage {
+    let columns = options.columns.min(frames.len() as u32);
+    let rows = (frames.len() as u32).div_ceil(columns);
+    let label_height = GLYPH_HEIGHT + FRAME_GAP / 2;
+    let frame_options = ImageOptions {
+        width: (options.width.saturating_sub(FRAME_GAP * (columns - 1)) / columns).max(1),
+        ..*options
+    };
+    let max_length = (MAX_LENGTH / rows)
+        .saturating_sub(label_height + FRAME_GAP)
+        .max(1);
+    let printed: Vec<RgbImage> = frames
+        .iter()
+        .map(|f| prepare_within(f, &frame_options, max_length))
+        .collect();
+
+    let cell_width = printed.iter().map(|f| f.width()).max().unwrap_or(1);
+    let cell_height = printed.iter().map(|f| f.height()).max().unwrap_or(1) + label_height;
+    let mut sheet = RgbImage::from_pixel(
+        columns * cell_width + (columns - 1) * FRAME_GAP,
+        rows * cell_height + (rows - 1) * FRAME_GAP,
+        Rgb([0xFF; 3]),
+    );
+    for (idx, frame) in printed.iter().enumerate() {
+        let (col, row) = (idx as u32 % columns, idx as u32 / columns);
+        let (x, y) = (
+            col * (cell_width + FRAME_GAP),
+            row * (cell_height + FRAME_GAP),
+        );
+
+        let label = format!("{}/{}", idx + 1, printed.len());
+        for (i, c) in label.bytes().enumerate() {
+            let left = x + i as u32 * GLYPH_WIDTH;
+            for (gx, gy) in
+                (0..GLYPH_WIDTH).flat_map(|gx| (0..GLYPH_HEIGHT).map(move |gy| (gx, gy)))
+            {
+                if left + gx < sheet.width() && font_dot(c, gx, gy) {
+                    sheet.put_pixel(left + gx, y + gy, Rgb([0x00; 3]));
+                }
+            }
+        }
+        imageops::replace(&mut sheet, frame, x, y + label_height);
+    }
+    sheet
+}
+
+/// Scale, adjust and dither an image for the printer
+pub fn prepare(image: &DynamicImage, options: &ImageOptions) -> RgbImage {
+    prepare_within(image, options, MAX_LENGTH)
+}
+
+/// Like `prepare`, but never enlarges the image, for pictures drawn dot by dot like Lua
+/// canvases. The printer centres anything narrower than the paper.
+pub fn prepare_at_size(image: &DynamicImage, options: &ImageOptions) -> RgbImage {
+    let rotate = orientation(image.dimensions(), options, MAX_LENGTH);
+    let (width, height) = match rotate {
+        Rotation::Cw90 | Rotation::Cw270 => (image.height(), image.width()),
+        _ => image.dimensions(),
+    };
+    let options = ImageOptions {
+        width: options.width.min(width),
+        rotate,
+        ..*options
+    };
+    prepare_within(image, &options, MAX_LENGTH.min(height))
+}
+
+/// Like `prepare`, but no longer than `max_length` dots
+fn prepare_within(image: &DynamicImage, options: &ImageOptions, max_length: u32) -> RgbImage {
+    let rotated;
+    let image = match orientation(image.dimensions(), options, max_length) {
+        Rotation::Cw90 => {
+            rotated = image.rotate90();
+            &rotated
+        }
+        Rotation::Cw180 => {
+            rotated = image.rotate180();
+            &rotated
+        }
+        Rotation::Cw270 => {
+            rotated = image.rotate270();
+            &rotated
+        }
+        Rotation::None | Rotation::Auto => image,
+    };
+
+    let mut gray = match image.width() == options.width && image.height() <= max_length {
+        true => image.to_luma8(),
+        false => image
+            .resize(options.width, max_length, FilterType::Triangle)
+            .to_luma8(),
+    };
+
+    // Dithering line art just makes its edges ragged
+    let line_art = options.enhance && is_line_art(image);
+    if options.enhance {
+        gray = enhance(&gray, line_art);
+    }
+    let method = match (options.dither, line_art) {
+        (Some(method), _) => method,
+        (None, true) => Dither::Threshold,
+        (None, false) => Dither::Floyd,
+    };
+
+    let mut levels: Vec<f32> = gray.pixels().map(|p| adjust(p.0[0], options)).collect();
+    dither(&mut levels, gray.width() as usize, method);
+
+    let (width, height) = gray.dimensions();
+    RgbImage::from_fn(width, height, |x, y| {
+        match levels[(y * width + x) as usize] < 0.5 {
+            true => Rgb([0x00; 3]),
+            false => Rgb([0xFF; 3]),
+        }
+    })
+}
+
+/// Which way to turn an image of the given size, deciding on automatic rotation
+fn orientation((width, height): (u32, u32), options: &ImageOptions, max_length: u32) -> Rotation {
+    // Printed dots per pixel, after fitting the width and length limits
+    let scale = |width: u32, height: u32| {
+        (options.width as f32 / width as f32).min(max_length as f32 / height as f32)
+    };
+    match options.rotate {
+        Rotation::Auto if scale(height, width) > scale(width, height) => Rotation::Cw90,
+        Rotation::Auto => Rotation::None,
+        rotation => rotation,
+    }
+}
+
+/// Screenshots, diagrams and the like, where almost every pixel is one of a few colours.
+/// Colours are compared roughly, so JPEG noise doesn't count as extra colours.
+fn is_line_art(image: &DynamicImage) -> bool {
+    let rgb = image.to_rgb8();
+    let step = (rgb.width() as usize * rgb.height() as usize / LINE_ART_SAMPLES).max(1);
+    let mut counts: HashMap<[u8; 3], usize> = HashMap::new();
+    for p in rgb.pixels().step_by(step) {
+        let [r, g, b] = p.0;
+        *counts.entry([r >> 4, g >> 4, b >> 4]).or_default() += 1;
+    }
+
+    let mut counts: Vec<usize> = counts.values().copied().collect();
+    counts.sort_unstable_by(|a, b| b.cmp(a));
+    let total: usize = counts.iter().sum();
+    let common: usize = counts.iter().take(LINE_ART_COLORS).sum();
+    common as f32 >= total as f32 * LINE_ART_COVERAGE
+}
+
+/// Auto levels, then for photos some histogram equalization, an unsharp mask and edge
+/// sharpening
+fn enhance(image: &GrayImage, line_art: bool) -> GrayImage {
+    let image = auto_levels(image);
+    if line_art {
+        return image;
+    }
+
+    let equalized = equalize(&image);
+    let mixed = GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        let (a, b) = (image.get_pixel(x, y).0[0], equalized.get_pixel(x, y).0[0]);
+        Luma([(a as f32 * (1. - EQUALIZE_AMOUNT) + b as f32 * EQUALIZE_AMOUNT) as u8])
+    });
+
+    let blurred = imageops::blur(&mixed, UNSHARP_SIGMA);
+    let sharp = GrayImage::from_fn(mixed.width(), mixed.height(), |x, y| {
+        let (v, b) = (
+            mixed.get_pixel(x, y).0[0] as f32,
+            blurred.get_pixel(x, y).0[0] as f32,
+        );
+        Luma([(v + (v - b) * UNSHARP_AMOUNT).clamp(0., 255.) as u8])
+    });
+
+    let (width, height) = sharp.dimensions();
+    let at = |x: i64, y: i64| {
+        let (x, y) = (x.clamp(0, width as i64 - 1), y.clamp(0, height as i64 - 1));
+        sharp.get_pixel(x as u32, y as u32).0[0] as f32
+    };
+    GrayImage::from_fn(width, height, |x, y| {
+        let (x, y) = (x as i64, y as i64);
+        let v = at(x, y);
+        let laplacian = 4. * v - at(x - 1, y) - at(x + 1, y) - at(x, y - 1) - at(x, y + 1);
+        Luma([(v + laplacian * EDGE_AMOUNT).clamp(0., 255.) as u8])
+    })
+}
+
+fn histogram(image: &GrayImage) -> [usize; 256] {
+    let mut histogram = [0; 256];
+    for p in image.pixels() {
+        histogram[p.0[0] as usize] += 1;
+    }
+    histogram
+}
+
+/// Stretch levels so the darkest pixels are black and the lightest white
+fn auto_levels(image: &GrayImage) -> GrayImage {
+    let histogram = histogram(image);
+    let clip = (image.len() as f32 * LEVELS_CLIP) as usize;
+    let low = clipped_level(&histogram, 0..256, clip) as f32;
+    let high = clipped_level(&histogram, (0..256).rev(), clip) as f32;
+    if high <= low {
+        return image.clone();
+    }
+
+    let mut out = image.clone();
+    for p in out.pixels_mut() {
+        p.0[0] = ((p.0[0] as f32 - low) * 255. / (high - low)).clamp(0., 255.) as u8;
+    }
+    out
+}
+
+/// First level, going in the given order, past the `clip` most extreme pixels
+fn clipped_level(
+    histogram: &[usize; 256],
+    levels: impl Iterator<Item = usize>,
+    clip: usize,
+) -> usize {
+    let mut seen = 0;
+    let mut level = 0;
+    for v in levels {
+        seen += histogram[v];
+        level = v;
+        if seen > clip {
+            break;
+        }
+    }
+    level
+}
+
+/// Spread levels out so each is used about as much as any other
+fn equalize(image: &GrayImage) -> GrayImage {
+    let histogram = histogram(image);
+    let mut cdf = [0; 256];
+    let mut total = 0;
+    for (v, count) in histogram.iter().enumerate() {
+        total += count;
+        cdf[v] = total;
+    }
+    let darkest = cdf.iter().copied().find(|&c| c > 0).unwrap_or(0);
+    if total == darkest {
+        return image.clone();
+    }
+
+    let mut out = image.clone();
+    for p in out.pixels_mut() {
+        let c = cdf[p.0[0] as usize] - darkest;
+        p.0[0] = (c * 255 / (total - darkest)) as u8;
+    }
+    out
+}
+
+/// Grey level from 0 (black) to 1 (white) after tone adjustments
+fn adjust(value: u8, options: &ImageOptions) -> f32 {
+    let v = value as f32 / 255.;
+    let v = (v - 0.5) * options.contrast + 0.5 + options.brightness;
+    let v = v.clamp(0., 1.).powf(1. / options.gamma);
+    match options.invert {
+        true => 1. - v,
+        false => v,
+    }
+}
+
+/// Error diffusion kernel: (dx, dy, weight) for each neighbour, and what the weights are divided by
+type Kernel = (&'static [(isize, usize, f32)], f32);
+
+const FLOYD: Kernel = (&[(1, 0, 7.), (-1, 1, 3.), (0, 1, 5.), (1, 1, 1.)], 16.);
+/// Only spreads 6/8 of the error
+const ATKINSON: Kernel = (
+    &[
+        (1, 0, 1.),
+        (2, 0, 1.),
+        (-1, 1, 1.),
+        (0, 1, 1.),
+        (1, 1, 1.),
+        (0, 2, 1.),
+    ],
+    8.,
+);
+const STUCKI: Kernel = (
+    &[
+        (1, 0, 8.),
+        (2, 0, 4.),
+        (-2, 1, 2.),
+        (-1, 1, 4.),
+        (0, 1, 8.),
+        (1, 1, 4.),
+        (2, 1, 2.),
+        (-2, 2, 1.),
+        (-1, 2, 2.),
+        (0, 2, 4.),
+        (1, 2, 2.),
+        (2, 2, 1.),
+    ],
+    42.,
+);
+
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Quantize grey levels to exactly 0 or 1 in place
+fn dither(levels: &mut [f32], width: usize, dither: Dither) {
+    let (kernel, divisor) = match dither {
+        Dither::Floyd => FLOYD,
+        Dither::Atkinson => ATKINSON,
+        Dither::Stucki => STUCKI,
+        Dither::Bayer => {
+            for (idx, v) in levels.iter_mut().enumerate() {
+                let threshold = (BAYER[idx / width % 8][idx % width % 8] as f32 + 0.5) / 64.;
+                *v = if *v < threshold { 0. } else { 1. };
+            }
+            return;
+        }
+        Dither::Threshold => {
+            for v in levels.iter_mut() {
+                *v = if *v < 0.5 { 0. } else { 1. };
+            }
+            return;
+        }
+    };
+
+    let height = levels.len() / width.max(1);
+    for y in 0..height {
+        for x in 0..width {
+            let old = levels[y * width + x];
+            let new = if old < 0.5 { 0. } else { 1. };
+            levels[y * width + x] = new;
+            let error = (old - new) / divisor;
+            for &(dx, dy, weight) in kernel {
+                let nx = x as isize + dx;
+                if nx >= 0 && (nx as usize) < width && y + dy < height {
+                    levels[(y + dy) * width + nx as usize] += error * weight;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fraction of an image's dots that are black
+    fn coverage(image: &RgbImage) -> f32 {
+        let black = image.pixels().filter(|p| p.0[0] == 0).count();
+        black as f32 / (image.width() * image.height()) as f32
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let (options, rest) = ImageOptions::parse_args(
+            "--dither bayer --invert --width 50% --contrast 20% https://a/b.png",
+        )
+        .unwrap();
+        assert_eq!(options.dither, Some(Dither::Bayer));
+        assert!(options.invert);
+        assert_eq!(options.width, PRINTER_DOTS_PER_LINE / 2);
+        assert!((options.contrast - 1.2).abs() < 1e-6);
+        assert_eq!(rest, "https://a/b.png");
+
+        // Text that happens to start with dashes is left alone
+        let (options, rest) = ImageOptions::parse_args("--- not an option").unwrap();
+        assert_eq!(options, ImageOptions::default());
+        assert_eq!(rest, "--- not an option");
+
+        assert!(ImageOptions::parse_args("--dither sideways").is_err());
+        assert!(ImageOptions::parse_args("--width 1000").is_err());
+        assert!(ImageOptions::parse_args("--gamma").is_err());
+    }
+
+    #[test]
+    fn test_prepare() {
+        let grey = DynamicImage::ImageLuma8(GrayImage::from_pixel(64, 64, Luma([0x80])));
+        for &dither in &[
+            Dither::Floyd,
+            Dither::Atkinson,
+            Dither::Stucki,
+            Dither::Bayer,
+        ] {
+            let options = ImageOptions {
+                dither: Some(dither),
+                width: 64,
+                ..ImageOptions::default()
+            };
+            let coverage = coverage(&prepare(&grey, &options));
+            assert!((coverage - 0.5).abs() < 0.05, "{:?}: {}", dither, coverage);
+        }
+
+        // Thresholding and adjustments
+        let mut options = ImageOptions {
+            dither: Some(Dither::Threshold),
+            width: 64,
+            ..ImageOptions::default()
+        };
+        assert_eq!(coverage(&prepare(&grey, &options)), 0.);
+        options.brightness = -0.1;
+        assert_eq!(coverage(&prepare(&grey, &options)), 1.);
+        options.invert = true;
+        assert_eq!(coverage(&prepare(&grey, &options)), 0.);
+
+        // Scaled to the requested width
+        let options = ImageOptions {
+            width: 32,
+            ..ImageOptions::default()
+        };
+        assert_eq!(prepare(&grey, &options).dimensions(), (32, 32));
+
+        // Drawings aren't enlarged, only shrunk to fit
+        let tiny = DynamicImage::ImageLuma8(GrayImage::new(1, 8));
+        let options = ImageOptions::default();
+        assert_eq!(prepare_at_size(&tiny, &options).dimensions(), (1, 8));
+        let wide = DynamicImage::ImageLuma8(GrayImage::new(PRINTER_DOTS_PER_LINE * 2, 100));
+        assert_eq!(
+            prepare_at_size(&wide, &options).dimensions(),
+            (PRINTER_DOTS_PER_LINE, 50)
+        );
+        let options = ImageOptions {
+            rotate: Rotation::Cw90,
+            ..options
+        };
+        assert_eq!(prepare_at_size(&tiny, &options).dimensions(), (8, 1));
+    }
+
+    #[test]
+    fn test_enhance() {
+        // Levels are stretched to black and white
+        let dull = GrayImage::from_fn(100, 1, |x, _| Luma([100 + x as u8 / 2]));
+        let levelled = auto_levels(&dull);
+        assert_eq!(levelled.get_pixel(0, 0).0[0], 0);
+        assert_eq!(levelled.get_pixel(99, 0).0[0], 255);
+        let equalized = equalize(&dull);
+        assert_eq!(equalized.get_pixel(99, 0).0[0], 255);
+
+        // Two colours and a little noise is line art, a gradient isn't
+        let diagram = RgbImage::from_fn(64, 64, |x, y| match (x / 8 + y / 8) % 2 {
+            0 => Rgb([0xF0 + (x % 3) as u8; 3]),
+            _ => Rgb([0x20, 0x40, 0x80]),
+        });
+        assert!(is_line_art(&DynamicImage::ImageRgb8(diagram.clone())));
+        let photo = GrayImage::from_fn(64, 64, |x, y| Luma([(x * 2 + y * 2) as u8]));
+        assert!(!is_line_art(&DynamicImage::ImageLuma8(photo)));
+
+        // Line art is thresholded rather than dithered, unless asked otherwise
+        let options = ImageOptions {
+            enhance: true,
+            width: 64,
+            ..ImageOptions::default()
+        };
+        let printed = prepare(&DynamicImage::ImageRgb8(diagram), &options);
+        assert_eq!(printed.get_pixel(1, 1).0, [0xFF; 3]);
+        assert!((0..8).all(|y| (8..16).all(|x| printed.get_pixel(x, y).0 == [0x00; 3])));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let (options, _) = ImageOptions::parse_args("--rotate auto").unwrap();
+        assert_eq!(options.rotate, Rotation::Auto);
+        assert!(ImageOptions::parse_args("--rotate 45").is_err());
+
+        // Wide images are turned to print larger, tall ones aren't
+        let wide = DynamicImage::ImageLuma8(GrayImage::new(1000, 200));
+        assert_eq!(
+            prepare(&wide, &options).dimensions(),
+            (PRINTER_DOTS_PER_LINE, 1920)
+        );
+        let tall = DynamicImage::ImageLuma8(GrayImage::new(100, 200));
+        assert_eq!(
+            prepare(&tall, &options).dimensions(),
+            (PRINTER_DOTS_PER_LINE, 768)
+        );
+
+        // Very long images are scaled down to the longest allowed
+        let panorama = DynamicImage::ImageLuma8(GrayImage::new(MAX_LENGTH * 2, 100));
+        let printed = prepare(&panorama, &options);
+        assert_eq!(printed.dimensions(), (50, MAX_LENGTH));
+    }
+
+    #[test]
+    fn test_flipbook() {
+        use image::codecs::gif::GifEncoder;
+        use image::{Frame, Rgba, RgbaImage};
+
+        // Ten frames, each a shade darker
+        let mut gif = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            let frames = (0..10).map(|i| {
+                Frame::new(RgbaImage::from_pixel(
+                    40,
+                    20,
+                    Rgba([250 - i * 25, 0, 0, 0xFF]),
+                ))
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+
+        let frames = gif_frames(&gif, 3).unwrap();
+        let shades: Vec<u8> = frames
+            .iter()
+            .map(|f| f.to_rgba8().get_pixel(0, 0).0[0])
+            .collect();
+        assert_eq!(shades.len(), 3);
+        assert!(shades[0] > shades[1] && shades[1] > shades[2]);
+
+        // A tiny file can claim a huge screen, which every frame would be decoded to
+        let mut huge = b"GIF89a\x10\x27\x10\x27\0\0\0".to_vec();
+        huge.extend_from_slice(b"\x2c\0\0\0\0\x01\0\x01\0\x80\0\0\0\xff\xff\xff");
+        huge.extend_from_slice(b"\x02\x02\x44\x01\0\x3b");
+        let err = gif_frames(&huge, 3).unwrap_err();
+        assert!(err.to_string().contains("too big"));
+
+        // Two columns of 188 dots, three rows with labels
+        let options = ImageOptions {
+            frames: 6,
+            columns: 2,
+            ..ImageOptions::default()
+        };
+        let sheet = load(&gif, &options).unwrap();
+        let cell_height = 94 + GLYPH_HEIGHT + FRAME_GAP / 2;
+        assert_eq!(
+            sheet.dimensions(),
+            (2 * 188 + FRAME_GAP, 3 * cell_height + 2 * FRAME_GAP)
+        );
+
+        // Stills are still stills
+        assert_eq!(load(&gif, &ImageOptions::default()).unwrap().height(), 192);
+    }
+}
@@ -0,0 +1,226 @@
+//! Breaks styled paragraphs into printed lines: word wrapping, hyphenating words too long for a
+//! line, and padding lines out to align them.
+
+use crate::markup::{Span, Style};
+use crate::printer::PRINTER_LINE_DOTS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// Text to be wrapped as a unit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paragraph {
+    pub align: Align,
+    pub spans: Vec<Span>,
+}
+
+/// One printed line
+pub type Line = Vec<Span>;
+
+/// Columns taken up by a character in the normal size. East Asian scripts and emoji are drawn
+/// twice as wide as everything else.
+pub fn char_columns(c: char) -> usize {
+    const WIDE: [(u32, u32); 10] = [
+        (0x1100, 0x115F),
+        (0x2E80, 0xA4CF),
+        (0xAC00, 0xD7A3),
+        (0xF900, 0xFAFF),
+        (0xFE30, 0xFE4F),
+        (0xFF00, 0xFF60),
+        (0xFFE0, 0xFFE6),
+        (0x1F300, 0x1F64F),
+        (0x1F900, 0x1F9FF),
+        (0x20000, 0x3FFFD),
+    ];
+    let c = c as u32;
+    match WIDE.iter().any(|&(start, end)| (start..=end).contains(&c)) {
+        true => 2,
+        false => 1,
+    }
+}
+
+/// Columns taken up by a styled character
+fn char_width(c: char, style: &Style) -> usize {
+    match style.double_width {
+        true => 2 * char_columns(c),
+        false => char_columns(c),
+    }
+}
+
+/// Wrap and align paragraphs to fit `columns` normal width characters per line
+pub fn layout(paragraphs: &[Paragraph], columns: usize) -> Vec<Line> {
+    let mut lines = Vec::new();
+    for paragraph in paragraphs {
+        let chars = paragraph
+            .spans
+            .iter()
+            .flat_map(|s| s.text.chars().map(move |c| (c, s.style)));
+        for row in wrap(chars, columns) {
+            lines.push(align(row, paragraph.align, columns));
+        }
+    }
+    lines
+}
+
+/// Greedy word wrap. Always produces at least one (possibly empty) row.
+fn wrap(chars: impl Iterator<Item = (char, Style)>, columns: usize) -> Vec<Vec<(char, Style)>> {
+    let mut rows = Vec::new();
+    let mut row: Vec<(char, Style)> = Vec::new();
+    let mut width = 0;
+    // Index into `row` of the last space, where it may be broken
+    let mut last_space: Option<usize> = None;
+
+    for (c, style) in chars {
+        let w = char_width(c, &style);
+        if width + w > columns && c == ' ' {
+            // Spaces at the end of a line just disappear
+            rows.push(std::mem::take(&mut row));
+            width = 0;
+            last_space = None;
+            continue;
+        }
+
+        while width + w > columns && !row.is_empty() {
+            match last_space.take() {
+                Some(idx) => {
+                    // Move the word being typed onto the next line
+                    let rest = row.split_off(idx + 1);
+                    row.pop();
+                    rows.push(std::mem::replace(&mut row, rest));
+                }
+                None => {
+                    // A single word longer than the line, break it with a hyphen
+                    let mut carry = Vec::new();
+                    while row_width(&row) + w > columns && row.len() > 1 {
+                        carry.insert(0, row.pop().unwrap());
+                    }
+                    let hyphen_style = row.last().map_or(style, |&(_, s)| s);
+                    row.push(('-', hyphen_style));
+                    rows.push(std::mem::replace(&mut row, carry));
+                }
+            }
+            width = row_width(&row);
+        }
+
+        // Don't start a wrapped line with a space
+        if c == ' ' && row.is_empty() && !rows.is_empty() {
+            continue;
+        }
+        if c == ' ' {
+            last_space = Some(row.len());
+        }
+        row.push((c, style));
+        width += w;
+    }
+    rows.push(row);
+    rows
+}
+
+fn row_width(row: &[(char, Style)]) -> usize {
+    row.iter().map(|(c, s)| char_width(*c, s)).sum()
+}
+
+/// Drop trailing spaces, pad to the alignment and merge characters back into spans
+fn align(mut row: Vec<(char, Style)>, align: Align, columns: usize) -> Line {
+    while matches!(row.last(), Some((' ', _))) {
+        row.pop();
+    }
+
+    let slack = columns.saturating_sub(row_width(&row));
+    let pad = match align {
+        Align::Left => 0,
+        Align::Center => slack / 2,
+        Align::Right => slack,
+    };
+
+    let mut line: Line = Vec::new();
+    if pad > 0 {
+        line.push(Span {
+            text: " ".repeat(pad),
+            style: Style::default(),
+        });
+    }
+    for (c, style) in row {
+        match line.last_mut() {
+            Some(span) if span.style == style => span.text.push(c),
+            _ => line.push(Span {
+                text: c.to_string(),
+                style,
+            }),
+        }
+    }
+    line
+}
+
+/// Paper used by laid out lines, in dots
+pub fn length_dots(lines: &[Line]) -> u32 {
+    lines
+        .iter()
+        .map(|line| match line.iter().any(|s| s.style.double_height) {
+            true => 2 * PRINTER_LINE_DOTS,
+            false => PRINTER_LINE_DOTS,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(text: &str, align: Align) -> Paragraph {
+        Paragraph {
+            align,
+            spans: vec![Span {
+                text: text.into(),
+                style: Style::default(),
+            }],
+        }
+    }
+
+    fn texts(lines: &[Line]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|l| l.iter().map(|s| s.text.as_str()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_wrap() {
+        let lines = layout(&[plain("the quick brown fox jumps", Align::Left)], 10);
+        assert_eq!(texts(&lines), vec!["the quick", "brown fox", "jumps"]);
+
+        let lines = layout(&[plain("a abcdefghijklmnop b", Align::Left)], 8);
+        assert_eq!(texts(&lines), vec!["a", "abcdefg-", "hijklmn-", "op b"]);
+
+        // Empty paragraphs still take up a line
+        let lines = layout(&[plain("", Align::Left), plain("x", Align::Left)], 8);
+        assert_eq!(texts(&lines), vec!["", "x"]);
+        assert_eq!(length_dots(&lines), 2 * PRINTER_LINE_DOTS);
+    }
+
+    #[test]
+    fn test_align() {
+        let lines = layout(&[plain("ab", Align::Center), plain("ab", Align::Right)], 6);
+        assert_eq!(texts(&lines), vec!["  ab", "    ab"]);
+
+        // Double width characters take two columns
+        let big = Style {
+            double_width: true,
+            ..Style::default()
+        };
+        let paragraph = Paragraph {
+            align: Align::Right,
+            spans: vec![Span {
+                text: "abc de".into(),
+                style: big,
+            }],
+        };
+        let lines = layout(&[paragraph], 8);
+        assert_eq!(texts(&lines), vec!["  abc", "    de"]);
+        assert_eq!(lines[0][1].style, big);
+    }
+}
@@ -1,6 +1,6 @@
-use anyhow::{ensure, format_err, Context, Result};
+use anyhow::{bail, ensure, format_err, Context, Result};
 use chrono::NaiveTime;
-use discord::model::Event;
+use discord::model::{ChannelId, Event};
 use discord::Discord;
 use log::{error, info, LevelFilter};
 use std::path::PathBuf;
@@ -14,15 +14,46 @@ use v4l::video::Capture;
 use v4l::Device;
 use v4l::FourCC;
 
-use image::RgbImage;
-use std::cell::RefCell;
+use image::{imageops, DynamicImage, GrayImage};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::rc::Rc;
 use std::sync::mpsc::{self, Receiver, Sender};
-use std::time::Duration;
-
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+mod backend;
+mod barcode;
+mod canvas;
+mod codepage;
+mod documents;
+mod emulator;
+mod escpos;
+mod fonts;
+mod imaging;
+mod layout;
+mod markup;
+mod paper;
+mod preview;
 mod printer;
+mod qr;
+mod queue;
+mod quota;
+mod sandbox;
+mod store;
+mod textfile;
 mod time_range;
-use printer::{PrintHandler, PrinterMsg};
+use backend::{BackendConfig, PrinterConfig, PrinterStatus};
+use fonts::{Face, Fonts};
+use imaging::ImageOptions;
+use paper::PaperLog;
+use barcode::{Barcode, BARCODE_HEIGHT};
+use canvas::Canvas;
+use printer::{PrintHandler, PrinterMsg, StatusMonitor, UserError};
+use queue::{Journal, PrintJob, PrintQueue, Source};
+use quota::{QuotaConfig, QuotaExceeded, Quotas};
+use sandbox::{Worker, WorkerMsg};
+use store::{Store, StoreValue, UserValues};
 use time_range::TimeRange;
 mod twitter_login;
 
@@ -37,6 +68,35 @@ struct Opt {
     #[structopt(long)]
     disable_printer: bool,
 
+    /// Printer backend: pos58, lp:<device>, tcp:<host[:port]>, file:<path> or emulator:<png>,
+    /// optionally followed by settings such as ?code_pages=cp858,cp1252
+    #[structopt(long, default_value = "pos58")]
+    printer: PrinterConfig,
+
+    /// Directory holding print jobs which haven't been printed yet
+    #[structopt(long, default_value = "print_queue")]
+    queue_dir: PathBuf,
+
+    /// Paper usage log
+    #[structopt(long, default_value = "paper_log.txt")]
+    paper_log: PathBuf,
+
+    /// Monochrome emoji font to use instead of the bundled Noto Emoji
+    #[structopt(long)]
+    emoji_font: Option<PathBuf>,
+
+    /// Extra TrueType fonts for characters the bundled and emoji fonts lack, such as CJK fonts
+    #[structopt(long)]
+    font: Vec<PathBuf>,
+
+    /// Discord user ids allowed to run operator commands, such as registering a new roll
+    #[structopt(long)]
+    operator: Vec<u64>,
+
+    /// Discord channel id to announce printer problems in
+    #[structopt(long)]
+    status_channel: Option<u64>,
+
     /// Logging path
     #[structopt(long, default_value = "print_bot.log")]
     log_path: PathBuf,
@@ -74,9 +134,41 @@ struct Opt {
     #[structopt(long)]
     max_instructions: Option<u32>,
 
+    /// Memory each Lua script may allocate, in megabytes
+    #[structopt(long, default_value = "32")]
+    max_memory_mb: usize,
+
+    /// Time each Lua script may run for, in seconds
+    #[structopt(long, default_value = "10")]
+    lua_timeout: u64,
+
+    /// Directory holding the values Lua scripts store between runs
+    #[structopt(long, default_value = "lua_store")]
+    lua_store: PathBuf,
+
+    /// Bytes each user's Lua scripts may store
+    #[structopt(long, default_value = "4096")]
+    lua_store_bytes: usize,
+
     /// Print a header with each message
     #[structopt(long)]
     header: bool,
+
+    /// Run Lua scripts for the bot, which starts this itself
+    #[structopt(long, hidden = true)]
+    lua_worker: bool,
+
+    /// Paper each user may use per hour, in millimetres
+    #[structopt(long)]
+    quota_hourly_mm: Option<u32>,
+
+    /// Paper each user may use per day, in millimetres
+    #[structopt(long)]
+    quota_daily_mm: Option<u32>,
+
+    /// Minimum time between print jobs from the same user, in seconds
+    #[structopt(long)]
+    quota_cooldown: Option<i64>,
 }
 
 struct CameraClient {
@@ -130,6 +222,9 @@ pub const HELP_COMMAND: &str = "!help";
 pub const PRINT_COMMAND: &str = "!print";
 pub const SHOW_COMMAND: &str = "!showme";
 pub const LUA_COMMAND: &str = "!lua";
+pub const PAPER_COMMAND: &str = "!paper";
+pub const QR_COMMAND: &str = "!qr";
+pub const BARCODE_COMMAND: &str = "!barcode";
 
 /// Log a result as an error
 pub fn log_result(res: Result<()>) {
@@ -138,14 +233,6 @@ pub fn log_result(res: Result<()>) {
     }
 }
 
-/// Log a fatal error and panic
-pub fn fatal_error(res: Result<()>) {
-    if res.is_err() {
-        log_result(res);
-        panic!("Fatal error");
-    }
-}
-
 fn parse_time(s: &str) -> Result<NaiveTime> {
     let mut s = s.split(':');
     match (s.next(), s.next()) {
@@ -160,139 +247,633 @@ fn lua_err(res: mlua::Error) -> anyhow::Error {
     format_err!("{}", res)
 }
 
-/// Role: Act as the communication layer between Discord, LUA, and the Printer
+/// A script sent to the Lua thread
+struct LuaRequest {
+    author: String,
+    author_id: String,
+    channel_id: ChannelId,
+    code: String,
+}
+
+/// A message for the Discord reply thread to send
+struct DiscordReply {
+    channel_id: ChannelId,
+    text: String,
+}
+
+/// Sends messages to Discord on behalf of threads other than the Discord thread
+fn discord_reply_thread(token: &str, replies: Receiver<DiscordReply>) -> Result<()> {
+    let discord = Discord::from_bot_token(token).context("login failed")?;
+    while let Ok(reply) = replies.recv() {
+        if let Err(e) = discord.send_message(reply.channel_id, &reply.text, "", false) {
+            error!("Failed to send reply: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Role: Act as the communication layer between Discord, the Lua worker, and the Printer
 fn lua_thread(
-    discord: Receiver<String>,
-    printer: Option<Sender<PrinterMsg>>,
-    max_instructions: u32,
-    max_bytes_text: u32,
-    max_bytes_image: u32,
+    discord: Receiver<LuaRequest>,
+    replies: Sender<DiscordReply>,
+    printer: Option<PrintQueue>,
+    store: Store,
+    timeout: Duration,
 ) -> Result<()> {
     info!("Lua thread started");
-    use mlua::StdLib;
-    let lua = mlua::Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::ALL_SAFE)
-        .map_err(lua_err)?;
+    let mut worker: Option<Worker> = None;
 
-    fn print_res(printer: &Option<Sender<PrinterMsg>>, msg: PrinterMsg) -> Result<()> {
+    fn print_res(printer: &Option<PrintQueue>, job: PrintJob) -> Result<()> {
         match printer {
-            Some(p) => Ok(p.send(msg)?),
-            None => Ok(match msg {
-                PrinterMsg::Image(img) => {
-                    let path = chrono::Local::now().format("lua-%H-%M-%S.png").to_string();
-                    eprintln!("Lua image {}x{}: {}", img.width(), img.height(), &path);
-                    img.save(&path)?;
+            Some(p) => p.submit(job),
+            None => {
+                for msg in job.msgs {
+                    match msg {
+                        PrinterMsg::Image(img) => {
+                            let path = chrono::Local::now().format("lua-%H-%M-%S.png").to_string();
+                            eprintln!("Lua image {}x{}: {}", img.width(), img.height(), &path);
+                            img.save(&path)?;
+                        }
+                        PrinterMsg::Text(txt) | PrinterMsg::Markup(txt) => {
+                            eprintln!("Lua text: {}", txt)
+                        }
+                        PrinterMsg::Barcode(barcode) => eprintln!(
+                            "Lua barcode ({}): {}",
+                            barcode.symbology().as_str(),
+                            barcode.data()
+                        ),
+                    }
                 }
-                PrinterMsg::Text(txt) => eprintln!("Lua text: {}", txt),
-            }),
+                Ok(())
+            }
         }
     }
 
     loop {
         // Receive
-        let msg = discord.recv()?;
+        let request = discord.recv()?;
 
         // If present, remove code block
-        let msg = msg
+        let msg = request
+            .code
             .trim_start()
             .trim_start_matches("```lua")
             .trim_start_matches("```")
             .trim_end_matches("```")
             .trim_end();
+
+        let values = match store.load(&request.author_id) {
+            Ok(values) => values.to_text(),
+            Err(e) => {
+                log_result(Err(e));
+                let _ = replies.send(DiscordReply {
+                    channel_id: request.channel_id,
+                    text: "Sorry, your stored values couldn't be loaded".into(),
+                });
+                continue;
+            }
+        };
+
+        // Start a fresh worker if there isn't one, or the last one died between scripts
+        let mut current = worker.take();
+        if let Some(w) = &mut current {
+            if !w.is_running() {
+                current = None;
+            }
+        }
+        let mut current = match current {
+            Some(w) => w,
+            None => match Worker::spawn() {
+                Ok(w) => {
+                    info!("Lua worker started");
+                    w
+                }
+                Err(e) => {
+                    log_result(Err(e));
+                    let _ = replies.send(DiscordReply {
+                        channel_id: request.channel_id,
+                        text: "Sorry, Lua isn't working right now".into(),
+                    });
+                    continue;
+                }
+            },
+        };
+
+        // Execute
+        let result = current.run(&request.author_id, values, msg.to_string(), timeout);
+        let msgs = match result {
+            Ok((msgs, values)) => {
+                worker = Some(current);
+
+                // Keep whatever was stored, even if the script failed later on
+                if let Some(values) = values {
+                    log_result(
+                        store
+                            .parse(&values)
+                            .and_then(|values| store.save(&request.author_id, &values)),
+                    );
+                }
+                msgs
+            }
+            Err(e) => {
+                // The worker is killed, and replaced for the next script
+                error!("{:#}", e);
+                vec![PrinterMsg::Text(
+                    "Lua crashed or hung, so everyone's globals have been reset".into(),
+                )]
+            }
+        };
+
+        let job = PrintJob {
+            source: Source::Lua,
+            author: request.author,
+            author_id: request.author_id,
+            msgs,
+        };
+        if let Err(e) = print_res(&printer, job) {
+            // Running out of quota is the user's problem, anything else is ours, but neither
+            // should stop the next script
+            let text = match e.downcast_ref::<QuotaExceeded>() {
+                Some(quota) => quota.to_string(),
+                None => {
+                    error!("{:#}", e);
+                    "Sorry, your script's output couldn't be printed".into()
+                }
+            };
+            let _ = replies.send(DiscordReply {
+                channel_id: request.channel_id,
+                text,
+            });
+        }
+    }
+}
+
+/// Limits on each Lua script
+struct LuaLimits {
+    max_instructions: u32,
+    max_memory: usize,
+    timeout: Duration,
+    max_bytes_text: u32,
+    max_bytes_image: u32,
+    max_store_bytes: usize,
+}
+
+impl LuaLimits {
+    fn new(opt: &Opt) -> Self {
+        Self {
+            max_instructions: opt.max_instructions.unwrap_or(u32::MAX),
+            max_memory: opt.max_memory_mb * 1024 * 1024,
+            timeout: Duration::from_secs(opt.lua_timeout),
+            max_bytes_text: opt.max_bytes_text.unwrap_or(u32::MAX),
+            max_bytes_image: opt.max_bytes_image.unwrap_or(u32::MAX),
+            max_store_bytes: opt.lua_store_bytes,
+        }
+    }
+}
+
+/// The Lua interpreter, with each user's globals
+struct LuaRunner {
+    lua: mlua::Lua,
+    /// Function building a fresh environment, from `LUA_ENVIRONMENT`
+    make_environment: mlua::RegistryKey,
+    environments: HashMap<String, mlua::RegistryKey>,
+    fonts: Arc<Fonts>,
+    limits: LuaLimits,
+}
+
+impl LuaRunner {
+    fn new(fonts: Arc<Fonts>, limits: LuaLimits) -> Result<Self> {
+        use mlua::StdLib;
+        let lua =
+            mlua::Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::ALL_SAFE)
+                .map_err(lua_err)?;
+
+        // Each user gets their own globals, kept between their scripts
+        let make_environment: mlua::Function = lua.load(LUA_ENVIRONMENT).eval().map_err(lua_err)?;
+        let make_environment = lua
+            .create_registry_value(make_environment)
+            .map_err(lua_err)?;
+
+        Ok(Self {
+            lua,
+            make_environment,
+            environments: HashMap::new(),
+            fonts,
+            limits,
+        })
+    }
+
+    /// Run a script for a user with the given stored values, returning what it printed and
+    /// their values if it changed them
+    fn run(
+        &mut self,
+        user_id: &str,
+        values: &str,
+        code: &str,
+    ) -> Result<(Vec<PrinterMsg>, Option<String>)> {
+        let lua = &self.lua;
+        let LuaLimits {
+            max_instructions,
+            max_memory,
+            timeout,
+            max_bytes_text,
+            max_bytes_image,
+            max_store_bytes,
+        } = self.limits;
         use mlua::Error;
 
+        // Everything printed by the script, submitted as one job once it finishes
+        let output = Rc::new(RefCell::new(Vec::new()));
+
+        let values = Rc::new(RefCell::new(UserValues::parse(values, max_store_bytes)?));
+
         // Text printing and byte exhaustion
         let remaining_bytes = Rc::new(RefCell::new(max_bytes_text as i64));
-        let lua_printer = printer.clone();
+        let lua_output = output.clone();
         let print = lua
             .create_function(move |_, v: String| {
                 *remaining_bytes.borrow_mut() -= v.as_bytes().len() as i64;
                 match *remaining_bytes.borrow() > 0 {
-                    true => Ok(print_res(&lua_printer, PrinterMsg::Text(v)).unwrap()),
+                    true => {
+                        lua_output.borrow_mut().push(PrinterMsg::Text(v));
+                        Ok(())
+                    }
                     false => Err(Error::RuntimeError("Text byte limit reached".into())),
                 }
             })
             .map_err(lua_err)?;
         lua.globals().set("print", print).map_err(lua_err)?;
 
-        // Image printing and byte exhaustion
-        let remaining_bytes = Rc::new(RefCell::new(max_bytes_image as i64));
-        let lua_printer = printer.clone();
+        // Image printing and byte exhaustion. Takes pixels or a canvas.
+        let image_bytes = Rc::new(RefCell::new(max_bytes_image as i64));
+        let remaining_bytes = image_bytes.clone();
+        let lua_output = output.clone();
         let print_image = lua
-            .create_function(move |_, v: Vec<bool>| {
-                *remaining_bytes.borrow_mut() -= v.len() as i64;
+            .create_function(move |lua, (v, options): (Value, Option<mlua::Table>)| {
+                let image = match v {
+                    Value::UserData(canvas) => canvas.borrow::<Canvas>()?.image().clone(),
+                    v => lua_image_to_gray(Vec::<Value>::from_lua(v, lua)?)
+                        .map_err(|e| Error::RuntimeError(e.to_string()))?,
+                };
+                // Printed no bigger than drawn, and paid for by the dots that come out
+                let options = lua_image_options(options)?;
+                let image = imaging::prepare_at_size(&DynamicImage::ImageLuma8(image), &options);
+                *remaining_bytes.borrow_mut() -= (image.width() * image.height()) as i64;
                 match *remaining_bytes.borrow() > 0 {
                     true => {
-                        let image = lua_image_to_rbgimage(v)
-                            .map_err(|e| Error::RuntimeError(e.to_string()))?;
-                        print_res(&lua_printer, PrinterMsg::Image(image))
-                            .map_err(|e| Error::RuntimeError(e.to_string()))
+                        lua_output.borrow_mut().push(PrinterMsg::Image(image));
+                        Ok(())
                     }
                     false => Err(Error::RuntimeError("Image byte limit reached".into())),
                 }
             })
             .map_err(lua_err)?;
+        lua.set_named_registry_value(canvas::PRINT_IMAGE, print_image.clone())
+            .map_err(lua_err)?;
         lua.globals().set("image", print_image).map_err(lua_err)?;
 
-        // Instruction exhaustion
+        // Canvases to draw on, printed with `image` above
+        let canvas_new = lua
+            .create_function(|lua, (width, height): (u32, u32)| {
+                let canvas =
+                    Canvas::new(width, height).map_err(|e| Error::RuntimeError(e.to_string()))?;
+                lua.create_userdata(canvas)
+            })
+            .map_err(lua_err)?;
+        let canvas_table = lua.create_table().map_err(lua_err)?;
+        canvas_table.set("new", canvas_new).map_err(lua_err)?;
+        lua.globals().set("Canvas", canvas_table).map_err(lua_err)?;
+
+        // Text drawn with fonts, as a canvas or printed straight away as a banner
+        let text_fonts = self.fonts.clone();
+        let text_image = lua
+            .create_function(move |lua, (text, options): (String, Option<mlua::Table>)| {
+                let canvas = lua_text_canvas(&text_fonts, &text, options, (48, false))?;
+                lua.create_userdata(canvas)
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("text_image", text_image).map_err(lua_err)?;
+        let banner_fonts = self.fonts.clone();
+        let banner = lua
+            .create_function(move |lua, (text, options): (String, Option<mlua::Table>)| {
+                let size = printer::PRINTER_DOTS_PER_LINE;
+                let canvas = lua_text_canvas(&banner_fonts, &text, options, (size, true))?;
+                let print_image: mlua::Function = lua.named_registry_value(canvas::PRINT_IMAGE)?;
+                print_image.call::<_, ()>(lua.create_userdata(canvas)?)
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("banner", banner).map_err(lua_err)?;
+
+        // QR codes count against the image budget
+        let remaining_bytes = image_bytes.clone();
+        let lua_output = output.clone();
+        let print_qr = lua
+            .create_function(move |_, text: String| {
+                let image = crate::qr::qr_image(&text)
+                    .map_err(|e| Error::RuntimeError(e.to_string()))?;
+                *remaining_bytes.borrow_mut() -= (image.width() * image.height()) as i64;
+                match *remaining_bytes.borrow() > 0 {
+                    true => {
+                        lua_output.borrow_mut().push(PrinterMsg::Image(image));
+                        Ok(())
+                    }
+                    false => Err(Error::RuntimeError("Image byte limit reached".into())),
+                }
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("qr", print_qr).map_err(lua_err)?;
+
+        // Barcodes too
+        let remaining_bytes = image_bytes.clone();
+        let lua_output = output.clone();
+        let print_barcode = lua
+            .create_function(move |_, (kind, data): (String, String)| {
+                let barcode = kind
+                    .parse()
+                    .and_then(|kind| Barcode::new(kind, &data))
+                    .map_err(|e| Error::RuntimeError(e.to_string()))?;
+                *remaining_bytes.borrow_mut() -=
+                    (BARCODE_HEIGHT * printer::PRINTER_DOTS_PER_LINE) as i64;
+                match *remaining_bytes.borrow() > 0 {
+                    true => {
+                        lua_output.borrow_mut().push(PrinterMsg::Barcode(barcode));
+                        Ok(())
+                    }
+                    false => Err(Error::RuntimeError("Image byte limit reached".into())),
+                }
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("barcode", print_barcode).map_err(lua_err)?;
+
+        // Values kept between runs, counted against the user's store quota
+        let get_values = values.clone();
+        let store_get = lua
+            .create_function(move |lua, key: String| {
+                Ok(match get_values.borrow().get(&key) {
+                    Some(StoreValue::String(s)) => Value::String(lua.create_string(s)?),
+                    Some(StoreValue::Integer(i)) => Value::Integer(*i),
+                    Some(StoreValue::Number(n)) => Value::Number(*n),
+                    Some(StoreValue::Boolean(b)) => Value::Boolean(*b),
+                    None => Value::Nil,
+                })
+            })
+            .map_err(lua_err)?;
+        let set_values = values.clone();
+        let store_set = lua
+            .create_function(move |_, (key, value): (String, Value)| {
+                let value = match value {
+                    Value::Nil => None,
+                    Value::String(s) => Some(StoreValue::String(s.to_str()?.to_string())),
+                    Value::Integer(i) => Some(StoreValue::Integer(i)),
+                    Value::Number(n) => Some(StoreValue::Number(n)),
+                    Value::Boolean(b) => Some(StoreValue::Boolean(b)),
+                    _ => {
+                        return Err(Error::RuntimeError(
+                            "Only strings, numbers and booleans can be stored".into(),
+                        ))
+                    }
+                };
+                set_values
+                    .borrow_mut()
+                    .set(&key, value)
+                    .map_err(|e| Error::RuntimeError(e.to_string()))
+            })
+            .map_err(lua_err)?;
+        let store_table = lua.create_table().map_err(lua_err)?;
+        store_table.set("get", store_get).map_err(lua_err)?;
+        store_table.set("set", store_set).map_err(lua_err)?;
+        lua.globals().set("store", store_table).map_err(lua_err)?;
+
+        if !self.environments.contains_key(user_id) {
+            let make_environment: mlua::Function = lua
+                .registry_value(&self.make_environment)
+                .map_err(lua_err)?;
+            let env: mlua::Table = make_environment.call(()).map_err(lua_err)?;
+            let env = lua.create_registry_value(env).map_err(lua_err)?;
+            self.environments.insert(user_id.to_string(), env);
+        }
+        let env: mlua::Table = lua
+            .registry_value(&self.environments[user_id])
+            .map_err(lua_err)?;
+
+        // Instruction and time exhaustion, checked every so often
+        let interval = max_instructions.min(LUA_HOOK_INTERVAL);
+        let instructions = Cell::new(0u32);
+        let start = Instant::now();
         lua.set_hook(
             mlua::HookTriggers {
-                every_nth_instruction: Some(max_instructions),
+                every_nth_instruction: Some(interval),
                 ..Default::default()
             },
             move |_, _| {
-                Err(mlua::Error::RuntimeError(
-                    "Instruction limit reached".into(),
-                ))
+                instructions.set(instructions.get().saturating_add(interval));
+                if instructions.get() >= max_instructions {
+                    return Err(mlua::Error::RuntimeError(
+                        "Instruction limit reached".into(),
+                    ));
+                }
+                if start.elapsed() > timeout {
+                    return Err(mlua::Error::RuntimeError("Time limit reached".into()));
+                }
+                Ok(())
             },
         )
         .map_err(lua_err)?;
 
+        // Memory exhaustion, on top of what's already in use
+        lua.set_memory_limit(lua.used_memory() + max_memory)
+            .map_err(lua_err)?;
+
         // Execute
-        match lua.load(&msg).eval::<mlua::MultiValue>() {
-            Err(mlua::Error::CallbackError { cause, .. }) => {
-                if let mlua::Error::RuntimeError(v) = cause.as_ref() {
-                    print_res(&printer, PrinterMsg::Text(format!("{}", v)))?;
-                } else {
-                    print_res(
-                        &printer,
-                        PrinterMsg::Text(format!("Callback error: {}", cause)),
-                    )?;
+        let result = lua
+            .load(code)
+            .set_environment(env)
+            .and_then(|chunk| chunk.eval::<mlua::MultiValue>());
+        let mut output = output.borrow_mut();
+        match result {
+            Err(mlua::Error::CallbackError { mut cause, .. }) => {
+                // Builtins calling builtins, like `canvas:print()`, nest their errors
+                while let mlua::Error::CallbackError { cause: inner, .. } = cause.as_ref() {
+                    cause = inner.clone();
+                }
+                match cause.as_ref() {
+                    mlua::Error::RuntimeError(v) => output.push(PrinterMsg::Text(format!("{}", v))),
+                    mlua::Error::MemoryError(_) => {
+                        output.push(PrinterMsg::Text(LUA_MEMORY_LIMIT.into()))
+                    }
+                    cause => output.push(PrinterMsg::Text(format!("Callback error: {}", cause))),
                 }
             }
-            Err(e) => print_res(&printer, PrinterMsg::Text(format!("Error: {}", e)))?,
-            Ok(v) => v
-                .iter()
-                .map(|v| print_res(&printer, PrinterMsg::Text(value_to_string(v))))
-                .collect::<Result<Vec<()>, _>>()
-                .map(|_| ())?,
+            Err(mlua::Error::MemoryError(_)) => {
+                output.push(PrinterMsg::Text(LUA_MEMORY_LIMIT.into()))
+            }
+            Err(e) => output.push(PrinterMsg::Text(format!("Error: {}", e))),
+            Ok(v) => output.extend(v.iter().map(|v| PrinterMsg::Text(value_to_string(v)))),
         }
 
-        // Remove limit
+        // Remove limits, and free whatever the script left behind
         lua.remove_hook();
+        lua.set_memory_limit(0).map_err(lua_err)?;
+        lua.gc_collect().map_err(lua_err)?;
+
+        // Everything printed, and whatever was stored even if the script failed later on
+        let values = values.borrow();
+        let values = match values.changed() {
+            true => Some(values.to_text()),
+            false => None,
+        };
+        Ok((output.drain(..).collect(), values))
     }
 }
 
-fn lua_image_to_rbgimage(image: Vec<bool>) -> Result<RgbImage> {
+/// Runs the scripts the Lua thread sends, in a process of its own
+fn lua_worker(opt: &Opt, fonts: Fonts) -> Result<()> {
+    let limits = LuaLimits::new(opt);
+    let max_memory = limits.max_memory;
+    let mut runner = LuaRunner::new(Arc::new(fonts), limits)?;
+
+    // Nothing after this can touch the rest of the system
+    let (mut input, mut pipe) = sandbox::take_pipes()?;
+    sandbox::restrict(max_memory)?;
+
+    while let Some(request) = sandbox::read_msg(&mut input)? {
+        let (user_id, values, code) = match request {
+            WorkerMsg::Run {
+                user_id,
+                values,
+                code,
+            } => (user_id, values, code),
+            _ => bail!("The Lua worker only runs scripts"),
+        };
+        let (printed, values) = runner.run(&user_id, &values, &code)?;
+        for msg in printed {
+            sandbox::write_msg(&mut pipe, &WorkerMsg::Print(msg))?;
+        }
+        if let Some(values) = values {
+            sandbox::write_msg(&mut pipe, &WorkerMsg::Store(values))?;
+        }
+        sandbox::write_msg(&mut pipe, &WorkerMsg::Done)?;
+    }
+    Ok(())
+}
+
+/// Instructions between checks of a script's limits
+const LUA_HOOK_INTERVAL: u32 = 1000;
+const LUA_MEMORY_LIMIT: &str = "Memory limit reached";
+
+/// Builds the environment each user's scripts run in. Their globals and copies of the standard
+/// library are their own, and everything else (`print`, `image`, `store` and so on) is looked up
+/// in the shared globals, which scripts can't reach.
+const LUA_ENVIRONMENT: &str = r#"
+local globals = _G
+local std = {}
+for name, value in pairs(globals) do
+    std[name] = value
+end
+-- The bot's own print is set on the globals for each script, so Lua's mustn't shadow it
+std.print = nil
+local load, pairs, type, setmetatable = load, pairs, type, setmetatable
+
+-- Strings' methods are the original string library
+getmetatable("").__metatable = false
+
+return function()
+    local env = {}
+    for name, value in pairs(std) do
+        if type(value) == "table" and value ~= globals then
+            local copy = {}
+            for k, v in pairs(value) do
+                copy[k] = v
+            end
+            value = copy
+        end
+        env[name] = value
+    end
+    env._G = env
+    if env.package then
+        env.package.loaded = {}
+        for name in pairs(std.package.loaded) do
+            env.package.loaded[name] = env[name]
+        end
+    end
+
+    -- Chunks loaded by scripts share their environment. Only text, as bytecode can crash Lua.
+    env.load = function(chunk, name, mode, chunk_env)
+        return load(chunk, name, "t", chunk_env or env)
+    end
+    return setmetatable(env, { __index = globals, __metatable = false })
+end
+"#;
+
+/// Pixels are `true` for black, `false` for white, or a brightness from 0 (black) to 255
+fn lua_image_to_gray(image: Vec<Value>) -> Result<GrayImage> {
     ensure!(
         image.len() as u32 % printer::PRINTER_DOTS_PER_LINE == 0,
         "Err: Img width != 384"
     );
-    let mut rgb = Vec::with_capacity(image.len() * 3);
+    let mut gray = Vec::with_capacity(image.len());
 
-    for &px in &image {
-        let px = if px { 0x00 } else { 0xFF };
-        rgb.extend(&[px; 3]);
+    for px in &image {
+        match canvas::brightness(px) {
+            Some(v) => gray.push(v),
+            None => bail!("Pixels should be true, false or a brightness from 0 to 255"),
+        }
     }
 
-    RgbImage::from_raw(
+    GrayImage::from_raw(
         printer::PRINTER_DOTS_PER_LINE,
         image.len() as u32 / printer::PRINTER_DOTS_PER_LINE,
-        rgb,
+        gray,
     )
-    .context("Failed to create rgb image")
+    .context("Failed to create gray image")
+}
+
+/// Text drawn with a font, going by a Lua table like `{font = "mono", size = 48, vertical = true}`
+/// with the given default size and direction
+fn lua_text_canvas(
+    fonts: &Fonts,
+    text: &str,
+    options: Option<mlua::Table>,
+    (mut size, mut vertical): (u32, bool),
+) -> mlua::Result<Canvas> {
+    let err = |e: anyhow::Error| mlua::Error::RuntimeError(e.to_string());
+    let mut face = Face::Sans;
+    if let Some(options) = options {
+        if let Some(name) = options.get::<_, Option<String>>("font")? {
+            face = name.parse().map_err(err)?;
+        }
+        size = options.get::<_, Option<u32>>("size")?.unwrap_or(size);
+        vertical = options.get::<_, Option<bool>>("vertical")?.unwrap_or(vertical);
+    }
+    let mut image = fonts.draw_text(text, face, size).map_err(err)?;
+    if vertical {
+        // Reading down the paper as it comes out
+        image = imageops::rotate90(&image);
+    }
+    Canvas::from_image(image).map_err(err)
 }
 
-use mlua::Value;
+/// Image options from a Lua table like `{dither = "bayer", contrast = 20, invert = true}`
+fn lua_image_options(table: Option<mlua::Table>) -> mlua::Result<ImageOptions> {
+    let mut options = ImageOptions::default();
+    for pair in table.into_iter().flat_map(|t| t.pairs::<String, Value>()) {
+        let (name, value) = pair?;
+        let value = match value {
+            Value::Boolean(true) => None,
+            Value::Boolean(false) => continue,
+            Value::Integer(i) => Some(i.to_string()),
+            Value::Number(n) => Some(n.to_string()),
+            Value::String(s) => Some(s.to_str()?.to_string()),
+            other => Some(format!("{:?}", other)),
+        };
+        options
+            .set(&name, value.as_deref())
+            .map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+    }
+    Ok(options)
+}
+
+use mlua::{FromLua, Value};
 fn value_to_string(value: &Value) -> String {
     match value {
         Value::Nil => "nil".into(),
@@ -314,13 +895,16 @@ fn value_to_string(value: &Value) -> String {
 fn discord_thread(
     token: &str,
     time_range: Option<TimeRange>,
-    lua_tx: Sender<String>,
-    printer: Option<Sender<PrinterMsg>>,
+    lua_tx: Sender<LuaRequest>,
+    printer: Option<PrintQueue>,
     camera: Option<CameraClient>,
+    paper_preview: Option<PathBuf>,
+    paper: Arc<Mutex<PaperLog>>,
+    operators: Vec<u64>,
     header: bool,
 ) -> Result<()> {
     // Set up printer concurrently with logging into Discord
-    let mut print_handler = printer.map(|tx| PrintHandler::new(tx)).transpose()?;
+    let mut print_handler = printer.map(PrintHandler::new).transpose()?;
 
     // Log in to Discord using a bot token from the environment
     info!("Logging into discord");
@@ -346,7 +930,7 @@ fn discord_thread(
 
                 // Run command
                 match cmd {
-                    PRINT_COMMAND => {
+                    PRINT_COMMAND | QR_COMMAND | BARCODE_COMMAND => {
                         // TODO: This should be calculated for the PRINTER and not for Discord!
                         if let Some(time_range) = time_range {
                             let (time, in_range) = time_range.check_local();
@@ -363,7 +947,25 @@ fn discord_thread(
                         );
 
                         if let Some(handler) = &mut print_handler {
-                            log_result(handler.handle_discord(message, header));
+                            let channel_id = message.channel_id;
+                            let res = match cmd {
+                                QR_COMMAND => handler.handle_qr(message, header),
+                                BARCODE_COMMAND => handler.handle_barcode(message, header),
+                                _ => handler.handle_discord(message, header),
+                            };
+                            if let Err(e) = res {
+                                // Tell people what they did wrong, log anything else
+                                let reply = match e.downcast_ref::<QuotaExceeded>() {
+                                    Some(quota) => Some(quota.to_string()),
+                                    None => e.downcast_ref::<UserError>().map(|e| e.to_string()),
+                                };
+                                match reply {
+                                    Some(msg) => {
+                                        discord.send_message(channel_id, &msg, "", false)?;
+                                    }
+                                    None => log_result(Err(e)),
+                                }
+                            }
                         } else {
                             discord.send_message(message.channel_id, SORRY_PRINTER, "", false)?;
                         }
@@ -378,11 +980,29 @@ fn discord_thread(
                             }
                         }
 
-                        lua_tx.send(message.content.trim_start_matches(LUA_COMMAND).to_string())?
+                        lua_tx.send(LuaRequest {
+                            author: format!(
+                                "{}#{}",
+                                message.author.name, message.author.discriminator
+                            ),
+                            author_id: message.author.id.0.to_string(),
+                            channel_id: message.channel_id,
+                            code: message.content.trim_start_matches(LUA_COMMAND).to_string(),
+                        })?
                     }
                     HELP_COMMAND => {
                         discord.send_message(message.channel_id, HELP_TEXT, "", false)?;
                     }
+                    PAPER_COMMAND => {
+                        let reply = match paper_command(&paper, &operators, &message) {
+                            Ok(reply) => reply,
+                            Err(e) => {
+                                error!("{:#}", e);
+                                SORRY_PAPER.into()
+                            }
+                        };
+                        discord.send_message(message.channel_id, &reply, "", false)?;
+                    }
                     SHOW_COMMAND => match camera
                         .as_ref()
                         .and_then(|c| c.capture(Duration::from_secs(2)))
@@ -401,9 +1021,25 @@ fn discord_thread(
                                 )
                                 .context("Failed to send image file!")?;
                         }
-                        None => {
-                            discord.send_message(message.channel_id, SORRY_CAMERA, "", false)?;
-                        }
+                        None => match paper_preview.as_ref().map(|p| emulated_paper_tail(p)) {
+                            Some(Ok(buf)) => {
+                                discord
+                                    .send_file(
+                                        message.channel_id,
+                                        "",
+                                        std::io::Cursor::new(buf),
+                                        "paper.png",
+                                    )
+                                    .context("Failed to send image file!")?;
+                            }
+                            Some(Err(e)) => {
+                                error!("{:#}", e);
+                                discord.send_message(message.channel_id, SORRY_CAMERA, "", false)?;
+                            }
+                            None => {
+                                discord.send_message(message.channel_id, SORRY_CAMERA, "", false)?;
+                            }
+                        },
                     },
                     _ => (),
                 }
@@ -421,8 +1057,53 @@ fn discord_thread(
     }
 }
 
+/// Report paper usage, or let an operator register a new roll
+fn paper_command(
+    paper: &Mutex<PaperLog>,
+    operators: &[u64],
+    message: &discord::model::Message,
+) -> Result<String> {
+    let mut args = message.content.split_whitespace().skip(1);
+    let usage = format!(
+        "Usage: `{}` or `{} roll <length in metres>`",
+        PAPER_COMMAND, PAPER_COMMAND
+    );
+    match (args.next(), args.next()) {
+        (None, _) => Ok(paper.lock().unwrap().report()),
+        (Some("roll"), _) if !operators.contains(&message.author.id.0) => {
+            Ok("Sorry, only operators can register a new roll".into())
+        }
+        (Some("roll"), Some(metres)) => match metres.parse::<f32>() {
+            Ok(m) if m > 0. => {
+                paper.lock().unwrap().new_roll((m * 1000.) as u32)?;
+                info!(
+                    "{}#{} registered a new {} m roll",
+                    message.author.name, message.author.discriminator, m
+                );
+                Ok(format!("Registered a new {} m roll", m))
+            }
+            _ => Ok(usage),
+        },
+        _ => Ok(usage),
+    }
+}
+
+/// The most recently printed part of the emulated paper roll, as a PNG
+fn emulated_paper_tail(path: &PathBuf) -> Result<Vec<u8>> {
+    use image::GenericImageView;
+    const PREVIEW_HEIGHT: u32 = 1536;
+    let mut roll = image::open(path).context("No paper has been printed yet")?;
+    let (width, height) = (roll.width(), roll.height());
+    let top = height.saturating_sub(PREVIEW_HEIGHT);
+    let tail = roll.crop(0, top, width, height - top);
+
+    let mut buf = Vec::new();
+    tail.write_to(&mut buf, image::ImageOutputFormat::Png)?;
+    Ok(buf)
+}
+
 fn twitter_thread(
-    printer: Option<Sender<PrinterMsg>>,
+    printer: Option<PrintQueue>,
     key: String,
     secret_key: String,
     camera: Option<CameraClient>,
@@ -443,7 +1124,7 @@ fn twitter_thread(
 }
 
 async fn twitter_thread_internal(
-    printer: Option<Sender<PrinterMsg>>,
+    printer: Option<PrintQueue>,
     key: String,
     secret_key: String,
     camera: Option<CameraClient>,
@@ -473,10 +1154,11 @@ async fn twitter_thread_internal(
             }
 
             // Get username
-            let user_name = match &t.user {
-                Some(u) => &u.screen_name,
+            let user = match &t.user {
+                Some(u) => u,
                 None => continue,
             };
+            let user_name = &user.screen_name;
 
             info!("Handling Tweet from {}", user_name);
 
@@ -486,9 +1168,27 @@ async fn twitter_thread_internal(
                 .trim_start_matches("@")
                 .trim_start_matches(&config.screen_name);
             let text = format!("{}: {}\n\n", user_name, tweet_text);
-            printer
-                .send(PrinterMsg::Text(text))
-                .context("Send to printer")?;
+            let job = PrintJob {
+                source: Source::Twitter,
+                author: user_name.clone(),
+                author_id: user.id.to_string(),
+                msgs: vec![PrinterMsg::Text(text)],
+            };
+            if let Err(e) = printer.submit(job) {
+                match e.downcast_ref::<QuotaExceeded>() {
+                    Some(quota) => {
+                        info!("Ignoring Tweet from {}: {}", user_name, quota);
+                        egg_mode::tweet::DraftTweet::new(quota.to_string())
+                            .in_reply_to(t.id)
+                            .auto_populate_reply_metadata(true)
+                            .send(&token)
+                            .await
+                            .context("Send tweet")?;
+                        continue;
+                    }
+                    None => return Err(e).context("Send to printer"),
+                }
+            }
 
             // Wait for printer to print
             tokio::time::delay_for(Duration::from_secs(1)).await;
@@ -521,6 +1221,15 @@ async fn twitter_thread_internal(
 fn main() -> Result<()> {
     // Arg parsing
     let opt = Opt::from_args();
+    let code_pages = opt.printer.code_pages.clone();
+
+    // Lua scripts are run by another copy of the bot, which does nothing else
+    if opt.lua_worker {
+        simple_logging::log_to_stderr(LevelFilter::Info);
+        let fonts = Fonts::load(&code_pages, opt.emoji_font.as_deref(), &opt.font)?;
+        return lua_worker(&opt, fonts);
+    }
+
     let begin_time = opt.begin_time.as_ref().map(|s| parse_time(s)).transpose()?;
     let end_time = opt.end_time.as_ref().map(|s| parse_time(s)).transpose()?;
     let time_range = begin_time.zip(end_time).map(|(b, e)| TimeRange(b, e));
@@ -528,30 +1237,73 @@ fn main() -> Result<()> {
     // Set up logging
     simple_logging::log_to_file(opt.log_path, LevelFilter::Info)?;
 
-    // Channel for Discord <-> printer thread communication
-    let printer = (!opt.disable_printer).then(|| {
-        let (sender, mut receiver) = mpsc::channel();
-        thread::spawn(move || loop {
-            crate::log_result(printer::printer_thread(&mut receiver))
-        });
-        sender
+    let quotas = Quotas::new(QuotaConfig {
+        hourly_mm: opt.quota_hourly_mm,
+        daily_mm: opt.quota_daily_mm,
+        cooldown: opt.quota_cooldown.map(chrono::Duration::seconds),
     });
 
+    let paper = Arc::new(Mutex::new(PaperLog::open(&opt.paper_log)?));
+
+    // Replies to Discord from other threads
+    let (reply_tx, reply_rx) = mpsc::channel::<DiscordReply>();
+    if let Some(token) = opt.discord_token.clone() {
+        std::thread::spawn(move || log_result(discord_reply_thread(&token, reply_rx)));
+    }
+
+    // Printer status changes, announced in the status channel if there is one
+    let (status_tx, status_rx) = mpsc::channel::<PrinterStatus>();
+    if let Some(channel_id) = opt.status_channel {
+        let status_replies = reply_tx.clone();
+        std::thread::spawn(move || {
+            for status in status_rx {
+                let _ = status_replies.send(DiscordReply {
+                    channel_id: ChannelId(channel_id),
+                    text: status.to_string(),
+                });
+            }
+        });
+    }
+
+    let fonts = Arc::new(Fonts::load(&code_pages, opt.emoji_font.as_deref(), &opt.font)?);
+
+    // Channel for Discord <-> printer thread communication
+    let printer = match opt.disable_printer {
+        true => None,
+        false => {
+            let journal = Arc::new(Journal::open(&opt.queue_dir)?);
+            let (sender, mut receiver) = mpsc::channel();
+            let backend = opt.printer.backend.clone();
+            let printer_fonts = fonts.clone();
+            let printer_journal = journal.clone();
+            let printer_paper = paper.clone();
+            let mut monitor = StatusMonitor::new(status_tx);
+            thread::spawn(move || loop {
+                if let Err(e) = printer::printer_thread(
+                    &backend,
+                    &printer_fonts,
+                    &printer_journal,
+                    &printer_paper,
+                    &mut monitor,
+                    &mut receiver,
+                ) {
+                    error!("{:#}", e);
+                    monitor.update(PrinterStatus::Offline);
+                }
+                // Don't spin while the printer is unplugged
+                thread::sleep(Duration::from_secs(5));
+            });
+            Some(PrintQueue::new(journal, sender, quotas))
+        }
+    };
+
     // Spawn Lua thread
-    let (lua_tx, lua_rx) = mpsc::channel::<String>();
-    let max_instructions = opt.max_instructions.unwrap_or(u32::MAX);
-    let max_bytes_text = opt.max_bytes_text.unwrap_or(u32::MAX);
-    let max_bytes_image = opt.max_bytes_image.unwrap_or(u32::MAX);
+    let (lua_tx, lua_rx) = mpsc::channel::<LuaRequest>();
+    let timeout = Duration::from_secs(opt.lua_timeout);
     let lua_printer = printer.clone();
-    let lua_thread = std::thread::spawn(move || {
-        lua_thread(
-            lua_rx,
-            lua_printer,
-            max_instructions,
-            max_bytes_text,
-            max_bytes_image,
-        )
-    });
+    let store = Store::open(&opt.lua_store, opt.lua_store_bytes)?;
+    let lua_thread =
+        std::thread::spawn(move || lua_thread(lua_rx, reply_tx, lua_printer, store, timeout));
 
     // Spawn camera thread
     let (discord_camera, twitter_camera) = if opt.disable_camera {
@@ -575,6 +1327,11 @@ fn main() -> Result<()> {
     };
 
     let header = opt.header;
+    let operators = opt.operator;
+    let paper_preview = match &opt.printer.backend {
+        BackendConfig::Emulator(path) if !opt.disable_printer => Some(path.clone()),
+        _ => None,
+    };
 
     // Spawn Discord thread
     let discord_printer = printer.clone();
@@ -586,6 +1343,9 @@ fn main() -> Result<()> {
                 lua_tx.clone(),
                 discord_printer.clone(),
                 discord_camera,
+                paper_preview,
+                paper,
+                operators,
                 header,
             ))
         });
@@ -607,13 +1367,19 @@ This bot uses a receipt printer to immortalize your messages on 58mm thermal pap
 If this command works, the printer _should_ be running. Have fun!
 
 __Commands__:
-`!print`: Print text or an image URL following this command, or attached images.
+`!print`: Print text or an image URL following this command, or attached images, SVGs, PDFs (the first 5 pages) and text files. Code files are printed with line numbers. Other links are printed as a card with the page's title, description, picture and a QR code.
+  Images can be tuned with `--dither floyd|atkinson|stucki|bayer|threshold`, `--contrast 20%`, `--brightness -10%`, `--gamma 1.5`, `--invert`, `--width 50%` and `--rotate auto|90|180|270` before the URL. Print a GIF as a flipbook with `--frames 6`, and `--columns 2` for a grid. `--enhance` sharpens photos and keeps screenshots crisp.
+  Text can be **bold**, __underlined__, ||inverted|| or a `# heading`, and `->centred<-` or `->right aligned->`.
 `!help`: Print this message
-`!showme`: Take a picture of the printer, and show it here.
+`!showme`: Take a picture of the printer (or the emulated paper), and show it here.
+`!paper`: Show how much paper has been used, and how much is left on the roll.
+`!qr`: Print the text following this command as a QR code.
+`!barcode`: Print a barcode, e.g. `!barcode ean13 400638133393`. Supports code128, ean13 and upca.
 ";
 
 const SORRY_PRINTER: &str = "Sorry, the printer has been disabled for now :(";
 const SORRY_CAMERA: &str = "Sorry, the camera has been disabled for now :(";
+const SORRY_PAPER: &str = "Sorry, the paper log couldn't be updated :(";
 
 fn sorry_asleep<T: chrono::TimeZone>(range: TimeRange, time: chrono::DateTime<T>) -> String
 where
@@ -622,3 +1388,91 @@ where
     let TimeRange(begin, end) = range;
     format!("Sorry, I'm asleep and the printer makes a bunch of noise. The current bot-local time is {} and the bot is set up to become active between {} and {} (timezone: UTC{}). Please try again later!", begin, end, time.format("%H:%M"), time.format("%:z"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runner() -> LuaRunner {
+        let limits = LuaLimits {
+            max_instructions: 1_000_000,
+            max_memory: 16 * 1024 * 1024,
+            timeout: Duration::from_secs(5),
+            max_bytes_text: 1000,
+            max_bytes_image: 1_000_000,
+            max_store_bytes: 64,
+        };
+        let fonts = Fonts::load(&[], None, &[]).unwrap();
+        LuaRunner::new(Arc::new(fonts), limits).unwrap()
+    }
+
+    fn printed(runner: &mut LuaRunner, user_id: &str, code: &str) -> Vec<String> {
+        let (msgs, _) = runner.run(user_id, "", code).unwrap();
+        msgs.into_iter()
+            .map(|msg| match msg {
+                PrinterMsg::Text(text) => text,
+                _ => panic!("Expected text"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lua_print() {
+        let mut runner = runner();
+        let (msgs, values) = runner.run("42", "", "print(\"hi\")").unwrap();
+        assert!(matches!(&msgs[..], [PrinterMsg::Text(t)] if t == "hi"));
+        assert!(values.is_none());
+
+        // Globals belong to their user, and last between scripts
+        assert!(printed(&mut runner, "42", "x = 1").is_empty());
+        assert_eq!(printed(&mut runner, "42", "print(tostring(x))"), ["1"]);
+        assert_eq!(printed(&mut runner, "43", "print(tostring(x))"), ["nil"]);
+        assert_eq!(
+            printed(&mut runner, "43", "string.upper = nil print(('a'):upper())"),
+            ["A"]
+        );
+
+        // Stored values come back if they changed
+        let (_, values) = runner.run("42", "", "store.set(\"n\", 2)").unwrap();
+        assert_eq!(values.as_deref(), Some("key n\ninteger 2\n"));
+    }
+
+    #[test]
+    fn test_lua_canvas_size() {
+        let mut runner = runner();
+        let code = "image(Canvas.new(1, 8)) image(Canvas.new(768, 10))";
+        let (msgs, _) = runner.run("42", "", code).unwrap();
+        let sizes: Vec<_> = msgs
+            .iter()
+            .map(|msg| match msg {
+                PrinterMsg::Image(image) => image.dimensions(),
+                _ => panic!("Expected an image"),
+            })
+            .collect();
+        assert_eq!(sizes, [(1, 8), (printer::PRINTER_DOTS_PER_LINE, 5)]);
+
+        // The budget pays for what's printed, not what was drawn
+        let code = "image(Canvas.new(3200, 3200))";
+        assert_eq!(runner.run("42", "", code).unwrap().0.len(), 1);
+    }
+
+    #[test]
+    fn test_lua_text_size() {
+        let mut runner = runner();
+        let code = "image(text_image('Hi', {size = 24})) image(text_image('Hi', {size = 48})) \
+                    banner('Hi', {size = 100})";
+        let (msgs, _) = runner.run("42", "", code).unwrap();
+        let sizes: Vec<_> = msgs
+            .iter()
+            .map(|msg| match msg {
+                PrinterMsg::Image(image) => image.dimensions(),
+                _ => panic!("Expected an image"),
+            })
+            .collect();
+        // Lines of text are `size` dots high, or wide for banners reading down the paper
+        assert_eq!(sizes[0].1, 24);
+        assert_eq!(sizes[1].1, 48);
+        assert!(sizes[1].0 < printer::PRINTER_DOTS_PER_LINE);
+        assert_eq!(sizes[2].0, 100);
+    }
+}
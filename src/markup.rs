@@ -0,0 +1,313 @@
+//! The subset of Discord's Markdown a receipt printer can show: `**bold**`, `__underline__`,
+//! `||spoilers||` (printed inverted) and `#` headings. Backslash escapes and `code` are respected.
+//! Paragraphs written `->like this<-` are centred, and `->like this->` right aligned.
+
+use crate::codepage::EncodedLine;
+use crate::escpos::{ESC, GS, LF};
+use crate::layout::{Align, Paragraph};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Style {
+    pub bold: bool,
+    pub underline: bool,
+    pub reverse: bool,
+    pub double_width: bool,
+    pub double_height: bool,
+}
+
+impl Style {
+    /// Inline styles on top of a line's heading style
+    fn over(self, base: Style) -> Style {
+        Style {
+            bold: self.bold || base.bold,
+            underline: self.underline || base.underline,
+            reverse: self.reverse || base.reverse,
+            double_width: self.double_width || base.double_width,
+            double_height: self.double_height || base.double_height,
+        }
+    }
+
+    /// Commands switching the printer to this style
+    fn escpos(&self) -> Vec<u8> {
+        let size = (self.double_width as u8) << 4 | self.double_height as u8;
+        let mut out = Vec::with_capacity(12);
+        out.extend_from_slice(&[ESC, b'E', self.bold as u8]);
+        out.extend_from_slice(&[ESC, b'-', self.underline as u8]);
+        out.extend_from_slice(&[GS, b'!', size]);
+        out.extend_from_slice(&[GS, b'B', self.reverse as u8]);
+        out
+    }
+}
+
+/// Text with a single style
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+}
+
+const PLAIN: Style = Style {
+    bold: false,
+    underline: false,
+    reverse: false,
+    double_width: false,
+    double_height: false,
+};
+
+/// Heading prefixes, longest first
+const HEADINGS: [(&str, Style); 3] = [
+    ("### ", Style { bold: true, ..PLAIN }),
+    (
+        "## ",
+        Style {
+            bold: true,
+            double_height: true,
+            ..PLAIN
+        },
+    ),
+    (
+        "# ",
+        Style {
+            bold: true,
+            double_width: true,
+            double_height: true,
+            ..PLAIN
+        },
+    ),
+];
+
+/// Picks out the style flag a marker toggles
+type Flag = fn(&mut Style) -> &mut bool;
+
+/// Inline markers and the style flag they toggle
+const MARKERS: [(&str, Flag); 3] = [
+    ("**", |s| &mut s.bold),
+    ("__", |s| &mut s.underline),
+    ("||", |s| &mut s.reverse),
+];
+
+/// Parse text into styled paragraphs, one per line. Styles don't carry over between lines.
+pub fn parse(text: &str) -> Vec<Paragraph> {
+    lines(text).map(parse_line).collect()
+}
+
+/// Unstyled paragraphs, one per line
+pub fn plain(text: &str) -> Vec<Paragraph> {
+    lines(text)
+        .map(|line| Paragraph {
+            align: Align::Left,
+            spans: vec![Span {
+                text: line.into(),
+                style: PLAIN,
+            }],
+        })
+        .collect()
+}
+
+/// Escape text so it prints as written, with no styles or alignment
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii_punctuation() {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Like `str::lines`, but a trailing newline still counts as an empty line
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.split('\n').map(|l| l.trim_end_matches('\r'))
+}
+
+fn parse_line(line: &str) -> Paragraph {
+    let (align, line) = match line.strip_prefix("->") {
+        Some(inner) if inner.ends_with("<-") => (Align::Center, &inner[..inner.len() - 2]),
+        Some(inner) if inner.ends_with("->") => (Align::Right, &inner[..inner.len() - 2]),
+        _ => (Align::Left, line),
+    };
+
+    let (base, rest) = HEADINGS
+        .iter()
+        .find_map(|(prefix, style)| Some((*style, line.strip_prefix(prefix)?)))
+        .unwrap_or((PLAIN, line));
+
+    let mut spans = Vec::new();
+    let mut text = String::new();
+    let mut inline = PLAIN;
+    let mut code = false;
+
+    let mut push_span = |text: &mut String, style: Style| {
+        if !text.is_empty() {
+            spans.push(Span {
+                text: std::mem::take(text),
+                style,
+            });
+        }
+    };
+
+    let mut idx = 0;
+    while let Some(c) = rest[idx..].chars().next() {
+        let tail = &rest[idx..];
+
+        // Nothing is special inside code, except the end of it
+        if code || c == '`' {
+            if c == '`' && (code || tail[1..].contains('`')) {
+                code = !code;
+            } else {
+                text.push(c);
+            }
+            idx += c.len_utf8();
+            continue;
+        }
+
+        if c == '\\' {
+            if let Some(escaped) = tail[1..].chars().next().filter(|c| c.is_ascii_punctuation()) {
+                text.push(escaped);
+                idx += 1 + escaped.len_utf8();
+                continue;
+            }
+        }
+
+        // Markers only count if they're closed later on, otherwise they're printed as typed
+        if let Some((marker, flag)) = MARKERS.iter().find(|(m, _)| tail.starts_with(m)) {
+            let open = *flag(&mut inline);
+            if open || tail[marker.len()..].contains(marker) {
+                push_span(&mut text, inline.over(base));
+                *flag(&mut inline) = !open;
+                idx += marker.len();
+                continue;
+            }
+        }
+
+        text.push(c);
+        idx += c.len_utf8();
+    }
+    push_span(&mut text, inline.over(base));
+    Paragraph { align, spans }
+}
+
+/// ESC/POS for laid out and encoded lines. Leaves the printer in its default style.
+pub fn to_escpos(lines: &[EncodedLine]) -> Vec<u8> {
+    let mut out = vec![ESC, b'a', 0];
+    let mut current = PLAIN;
+    let mut code_page = None;
+    for line in lines {
+        if let Some(page) = line.code_page.filter(|&p| Some(p) != code_page) {
+            out.extend_from_slice(&page.select());
+            code_page = Some(page);
+        }
+        for (style, bytes) in &line.spans {
+            if *style != current {
+                out.extend(style.escpos());
+                current = *style;
+            }
+            out.extend_from_slice(bytes);
+        }
+        out.push(LF);
+    }
+    out.extend(PLAIN.escpos());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codepage::{self, CodePage};
+    use crate::layout::layout;
+
+    fn span(text: &str, style: Style) -> Span {
+        Span {
+            text: text.into(),
+            style,
+        }
+    }
+
+    fn spans(text: &str) -> Vec<Vec<Span>> {
+        parse(text).into_iter().map(|p| p.spans).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let bold = Style {
+            bold: true,
+            ..Style::default()
+        };
+        let plain = Style::default();
+
+        assert_eq!(
+            spans("a **b** c"),
+            vec![vec![span("a ", plain), span("b", bold), span(" c", plain)]]
+        );
+
+        // Unclosed markers, escapes and code are printed as typed
+        assert_eq!(spans("2 ** 3"), vec![vec![span("2 ** 3", plain)]]);
+        assert_eq!(spans("\\**a\\**"), vec![vec![span("**a**", plain)]]);
+        assert_eq!(spans("`**a**`"), vec![vec![span("**a**", plain)]]);
+
+        // Nesting, and styles ending with the line
+        let both = Style {
+            underline: true,
+            ..bold
+        };
+        assert_eq!(
+            spans("**__a__ ||b||**\n**c"),
+            vec![
+                vec![
+                    span("a", both),
+                    span(" ", bold),
+                    span(
+                        "b",
+                        Style {
+                            reverse: true,
+                            ..bold
+                        }
+                    )
+                ],
+                vec![span("**c", plain)],
+            ]
+        );
+
+        // Headings are bold already
+        let heading = spans("# **Hi**")[0][0].style;
+        assert!(heading.bold && heading.double_width && heading.double_height);
+        assert_eq!(spans("#hashtag"), vec![vec![span("#hashtag", plain)]]);
+
+        // Alignment
+        let paragraphs = parse("->**a**<-\n->b->\n->c");
+        assert_eq!(paragraphs[0].align, Align::Center);
+        assert_eq!(paragraphs[0].spans, vec![span("a", bold)]);
+        assert_eq!(paragraphs[1].align, Align::Right);
+        assert_eq!(paragraphs[2].align, Align::Left);
+        assert_eq!(paragraphs[2].spans, vec![span("->c", plain)]);
+
+        // Escaped text comes back as written
+        let text = "->`**a**` \\ # ||b||<-";
+        assert_eq!(spans(&escape(text)), vec![vec![span(text, plain)]]);
+    }
+
+    #[test]
+    fn test_escpos() {
+        let encode = |text: &str| -> Vec<EncodedLine> {
+            let lines = layout(&parse(text), 32);
+            lines
+                .iter()
+                .map(|l| codepage::encode(l, &[CodePage::Cp437]).unwrap())
+                .collect()
+        };
+
+        let bytes = to_escpos(&encode("a **b**"));
+        assert_eq!(&bytes[..5], &[ESC, b'a', 0, b'a', b' ']);
+        assert_eq!(&bytes[5..8], &[ESC, b'E', 1]);
+        assert_eq!(bytes[17], b'b');
+        assert_eq!(bytes[18], LF);
+        assert!(bytes.ends_with(&Style::default().escpos()));
+
+        // Code pages are only selected when needed
+        let bytes = to_escpos(&encode("a\n\u{e9}\n\u{e8}"));
+        assert_eq!(&bytes[3..5], &[b'a', LF]);
+        assert_eq!(&bytes[5..10], &[ESC, b't', 0, 0x82, LF]);
+        assert_eq!(&bytes[10..12], &[0x8A, LF]);
+    }
+}
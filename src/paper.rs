@@ -0,0 +1,247 @@
+use anyhow::{format_err, Context, Result};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::printer::PRINTER_DOTS_PER_MM;
+
+/// The first moment of a day. Where a DST change skips midnight, that's the first time after it
+/// which exists.
+fn start_of_day<Tz: TimeZone>(tz: &Tz, date: NaiveDate) -> DateTime<Tz> {
+    let midnight = date.and_hms_opt(0, 0, 0).expect("Midnight is a valid time");
+    (0..24)
+        .find_map(|hour| {
+            tz.from_local_datetime(&(midnight + Duration::hours(hour)))
+                .earliest()
+        })
+        .unwrap_or_else(|| tz.from_utc_datetime(&midnight))
+}
+
+/// Paper used by one job
+struct Usage {
+    time: DateTime<Local>,
+    source: String,
+    author: String,
+    dots: u32,
+}
+
+/// Running log of paper usage, persisted as a tab separated file.
+/// Lines are either `print <time> <source> <author> <dots>` or `roll <time> <length in mm>`.
+pub struct PaperLog {
+    path: PathBuf,
+    usage: Vec<Usage>,
+    /// When the current roll was put in, and its length in mm
+    roll: Option<(DateTime<Local>, u32)>,
+}
+
+/// Totals over some period
+#[derive(Default)]
+pub struct UsageSummary {
+    pub jobs: usize,
+    pub dots: u32,
+    pub by_source: HashMap<String, u32>,
+    pub by_author: HashMap<String, u32>,
+}
+
+impl PaperLog {
+    /// Load the log, creating it if it doesn't exist
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut log = Self {
+            path,
+            usage: Vec::new(),
+            roll: None,
+        };
+
+        let text = match fs::read_to_string(&log.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(log),
+            Err(e) => return Err(e).context("Failed to read paper log"),
+        };
+
+        for (idx, line) in text.lines().enumerate() {
+            log.parse_line(line)
+                .with_context(|| format!("Paper log line {}", idx + 1))?;
+        }
+        Ok(log)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<()> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            ["print", time, source, author, dots] => self.usage.push(Usage {
+                time: parse_time(time)?,
+                source: source.to_string(),
+                author: author.to_string(),
+                dots: dots.parse()?,
+            }),
+            ["roll", time, length_mm] => self.roll = Some((parse_time(time)?, length_mm.parse()?)),
+            _ => return Err(format_err!("Malformed line: {}", line)),
+        }
+        Ok(())
+    }
+
+    fn append(&self, line: &str) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context("Failed to open paper log")?;
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+
+    /// Record paper used by a job
+    pub fn record(&mut self, source: &str, author: &str, dots: u32) -> Result<()> {
+        // Tabs and newlines would break the format
+        let author: String = author.chars().filter(|c| !c.is_control()).collect();
+        let usage = Usage {
+            time: Local::now(),
+            source: source.to_string(),
+            author,
+            dots,
+        };
+        self.append(&format!(
+            "print\t{}\t{}\t{}\t{}",
+            usage.time.to_rfc3339(),
+            usage.source,
+            usage.author,
+            usage.dots
+        ))?;
+        self.usage.push(usage);
+        Ok(())
+    }
+
+    /// Note that a fresh roll of the given length has been put in
+    pub fn new_roll(&mut self, length_mm: u32) -> Result<()> {
+        let now = Local::now();
+        self.append(&format!("roll\t{}\t{}", now.to_rfc3339(), length_mm))?;
+        self.roll = Some((now, length_mm));
+        Ok(())
+    }
+
+    /// Totals since the given time
+    pub fn summary_since(&self, since: DateTime<Local>) -> UsageSummary {
+        let mut summary = UsageSummary::default();
+        for usage in self.usage.iter().filter(|u| u.time >= since) {
+            summary.jobs += 1;
+            summary.dots += usage.dots;
+            *summary.by_source.entry(usage.source.clone()).or_default() += usage.dots;
+            *summary.by_author.entry(usage.author.clone()).or_default() += usage.dots;
+        }
+        summary
+    }
+
+    /// Estimated paper left on the current roll in mm, if one was registered
+    pub fn remaining_mm(&self) -> Option<i64> {
+        let (since, length_mm) = self.roll?;
+        let used = self.summary_since(since).dots / PRINTER_DOTS_PER_MM;
+        Some(length_mm as i64 - used as i64)
+    }
+
+    /// Human readable report for the `!paper` command
+    pub fn report(&self) -> String {
+        let now = Local::now();
+        let today = now.naive_local().date();
+        let midnight = start_of_day(&Local, today);
+        let week_start = start_of_day(
+            &Local,
+            today - Duration::days(now.weekday().num_days_from_monday() as i64),
+        );
+
+        let today = self.summary_since(midnight);
+        let week = self.summary_since(week_start);
+
+        let mut text = String::from("**Paper usage**\n");
+        text.push_str(&format!(
+            "Today: {} in {} jobs\n",
+            format_length(today.dots),
+            today.jobs
+        ));
+        text.push_str(&format!(
+            "This week: {} in {} jobs\n",
+            format_length(week.dots),
+            week.jobs
+        ));
+
+        let mut sources: Vec<_> = week.by_source.iter().collect();
+        sources.sort_by(|a, b| b.1.cmp(a.1));
+        for (source, dots) in sources {
+            text.push_str(&format!("  {}: {}\n", source, format_length(*dots)));
+        }
+
+        let mut authors: Vec<_> = week.by_author.iter().collect();
+        authors.sort_by(|a, b| b.1.cmp(a.1));
+        if !authors.is_empty() {
+            text.push_str("Top printers this week:\n");
+        }
+        for (author, dots) in authors.into_iter().take(5) {
+            text.push_str(&format!("  {}: {}\n", author, format_length(*dots)));
+        }
+
+        match self.remaining_mm() {
+            Some(mm) => text.push_str(&format!(
+                "About {} left on the roll",
+                format_length(mm.max(0) as u32 * PRINTER_DOTS_PER_MM)
+            )),
+            None => text.push_str("No roll has been registered, so I don't know how much is left"),
+        }
+        text
+    }
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Local>> {
+    Ok(DateTime::parse_from_rfc3339(s)?.with_timezone(&Local))
+}
+
+/// Format a length in dots as millimetres or metres
+pub fn format_length(dots: u32) -> String {
+    let mm = dots / PRINTER_DOTS_PER_MM;
+    match mm >= 1000 {
+        true => format!("{:.2} m", mm as f32 / 1000.),
+        false => format!("{} mm", mm),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paper_log() {
+        let path = std::env::temp_dir().join(format!("print_bot_paper_{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut log = PaperLog::open(&path).unwrap();
+        assert_eq!(log.remaining_mm(), None);
+        log.new_roll(30_000).unwrap();
+        log.record("discord", "someone#1234", 800).unwrap();
+        log.record("lua", "someone#1234", 80).unwrap();
+        log.record("twitter", "bird", 8).unwrap();
+
+        // Survives a reload
+        let log = PaperLog::open(&path).unwrap();
+        let summary = log.summary_since(Local::now() - Duration::hours(1));
+        assert_eq!(summary.jobs, 3);
+        assert_eq!(summary.dots, 888);
+        assert_eq!(summary.by_author["someone#1234"], 880);
+        assert_eq!(summary.by_source["twitter"], 8);
+        assert_eq!(log.remaining_mm(), Some(30_000 - 111));
+
+        let day = NaiveDate::from_ymd_opt(2021, 3, 14).unwrap();
+        let tz = chrono::FixedOffset::west_opt(8 * 3600).unwrap();
+        assert_eq!(
+            start_of_day(&tz, day).naive_local(),
+            day.and_hms_opt(0, 0, 0).unwrap()
+        );
+        let now = Local::now();
+        assert!(start_of_day(&Local, now.naive_local().date()) <= now);
+
+        assert_eq!(format_length(800), "100 mm");
+        assert_eq!(format_length(12_000), "1.50 m");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
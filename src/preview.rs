@@ -0,0 +1,238 @@
+//! Link previews from the OpenGraph and Twitter card metadata in a web page, falling back to the
+//! page's own title and description.
+
+use anyhow::{anyhow, Context, Result};
+use hyper::header::ContentType;
+use hyper::mime::{Mime, SubLevel, TopLevel};
+use hyper::{Client, Url};
+use std::collections::HashMap;
+
+use crate::printer;
+
+/// Longest description printed, in characters
+const MAX_DESCRIPTION: usize = 280;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Preview {
+    pub site_name: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Possibly relative to the page
+    pub image: Option<String>,
+}
+
+impl Preview {
+    /// Whether there's anything worth printing
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.description.is_none() && self.image.is_none()
+    }
+}
+
+/// Download a web page and pick out its preview metadata. Returns where the page ended up, which
+/// relative image links are resolved against.
+pub fn fetch(client: &Client, url: Url) -> Result<(Url, Preview)> {
+    let res = client.get(url).send().context("Page download failed")?;
+    if !res.status.is_success() {
+        return Err(anyhow!("Server replied {}", res.status));
+    }
+    match res.headers.get::<ContentType>() {
+        Some(ContentType(Mime(TopLevel::Text, SubLevel::Html, _))) => {}
+        Some(ContentType(Mime(TopLevel::Application, SubLevel::Ext(ext), _)))
+            if ext == "xhtml+xml" => {}
+        other => return Err(anyhow!("Not a web page: {:?}", other)),
+    }
+    let page_url = res.url.clone();
+    let html = printer::read_limited(res).context("Page read failed")?;
+    let preview = parse(&String::from_utf8_lossy(&html));
+    if preview.is_empty() {
+        return Err(anyhow!("Nothing to preview"));
+    }
+    Ok((page_url, preview))
+}
+
+/// Pick the metadata out of a page
+pub fn parse(html: &str) -> Preview {
+    // Lowercasing ASCII keeps byte offsets the same, so searches can be case insensitive
+    let lower = html.to_ascii_lowercase();
+    let mut meta = HashMap::new();
+    let mut idx = 0;
+    while let Some(start) = lower[idx..].find("<meta").map(|i| i + idx) {
+        let end = match lower[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let attrs = attributes(&html[start + "<meta".len()..end]);
+        let key = attrs.get("property").or_else(|| attrs.get("name"));
+        if let (Some(key), Some(content)) = (key, attrs.get("content")) {
+            meta.entry(key.to_ascii_lowercase())
+                .or_insert_with(|| clean(content));
+        }
+        idx = end;
+    }
+
+    let title = lower.find("<title").and_then(|start| {
+        let open = start + lower[start..].find('>')? + 1;
+        let close = open + lower[open..].find("</title")?;
+        Some(clean(&html[open..close]))
+    });
+
+    let mut pick = |keys: &[&str]| {
+        keys.iter()
+            .find_map(|k| meta.remove(*k))
+            .filter(|v| !v.is_empty())
+    };
+    let description = pick(&["og:description", "twitter:description", "description"]).map(|d| {
+        match d.chars().count() > MAX_DESCRIPTION {
+            true => format!(
+                "{}...",
+                d.chars().take(MAX_DESCRIPTION - 3).collect::<String>()
+            ),
+            false => d,
+        }
+    });
+    Preview {
+        site_name: pick(&["og:site_name", "application-name"]),
+        title: pick(&["og:title", "twitter:title"]).or(title.filter(|t| !t.is_empty())),
+        description,
+        image: pick(&[
+            "og:image",
+            "og:image:url",
+            "twitter:image",
+            "twitter:image:src",
+        ]),
+    }
+}
+
+/// Attributes of a tag, with lowercase names
+fn attributes(tag: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = tag;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        if name_end == 0 {
+            return attrs;
+        }
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        let value = match rest.strip_prefix('=') {
+            Some(after) => {
+                let after = after.trim_start();
+                match after.chars().next() {
+                    Some(quote @ '"') | Some(quote @ '\'') => {
+                        let end = after[1..].find(quote).map_or(after.len(), |e| e + 1);
+                        rest = after.get(end + 1..).unwrap_or("");
+                        &after[1..end]
+                    }
+                    _ => {
+                        let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                        rest = &after[end..];
+                        &after[..end]
+                    }
+                }
+            }
+            None => "",
+        };
+        attrs.insert(name, value.to_string());
+    }
+}
+
+/// Decode entities and collapse whitespace
+fn clean(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest.find(';').filter(|&end| end <= 10).and_then(|end| {
+            let c = match &rest[1..end] {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                "nbsp" => ' ',
+                entity => {
+                    let code = entity.strip_prefix('#')?;
+                    let code = match code.strip_prefix('x').or_else(|| code.strip_prefix('X')) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => code.parse().ok()?,
+                    };
+                    std::char::from_u32(code)?
+                }
+            };
+            Some((c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let html = r#"<!DOCTYPE html><html><head>
+            <TITLE>Fallback &amp; title</TITLE>
+            <meta charset=utf-8>
+            <meta name="twitter:title" content="Twitter title">
+            <meta property="og:title" content="Fish &#x26; chips &#8212; &quot;best&quot;" />
+            <meta content='A   long
+                day' property='og:description'>
+            <meta name=twitter:image content=/img/card.png>
+            <meta property="og:site_name" content="Example">
+        </head><body><meta property="og:title" content="Ignored"></body></html>"#;
+        assert_eq!(
+            parse(html),
+            Preview {
+                site_name: Some("Example".into()),
+                title: Some("Fish & chips \u{2014} \"best\"".into()),
+                description: Some("A long day".into()),
+                image: Some("/img/card.png".into()),
+            }
+        );
+
+        // Plain pages still have a title
+        let preview = parse("<html><head><title>\n  Hello </title></head></html>");
+        assert_eq!(preview.title.as_deref(), Some("Hello"));
+        assert_eq!(preview.description, None);
+        assert!(parse("<p>nothing & <meta</p>").is_empty());
+
+        // Long descriptions are cut short
+        let long = format!(
+            r#"<meta name="description" content="{}">"#,
+            "a".repeat(1000)
+        );
+        assert_eq!(parse(&long).description.unwrap().len(), MAX_DESCRIPTION);
+    }
+
+    #[test]
+    fn test_fetch() {
+        let server = printer::test_server();
+        let client = Client::new();
+        let url = |path: &str| Url::parse(&format!("{}{}", server, path)).unwrap();
+        let (page, preview) = fetch(&client, url("/article")).unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Fish & chips"));
+        assert_eq!(page.join(&preview.image.unwrap()).ok(), Some(url("/photo")));
+
+        // Only web pages with something to show
+        assert!(fetch(&client, url("/page.png")).is_err());
+        assert!(fetch(&client, url("/photo")).is_err());
+        assert!(fetch(&client, url("/missing")).is_err());
+    }
+}
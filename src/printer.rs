@@ -1,89 +1,289 @@
 use anyhow::{Context, Result, anyhow};
 use discord::model::Message;
-use dither::prelude::*;
 use escposify::{img::Image as EscImage, printer::Printer};
 use hyper::client::IntoUrl;
+use hyper::client::Response;
+use hyper::header::{ContentType, Range};
+use hyper::mime::{Mime, SubLevel, TopLevel};
 use hyper::net::HttpsConnector;
 use hyper::Client;
 use hyper::Url;
 use hyper_native_tls::NativeTlsClient;
-use image::GenericImageView;
 use log::{error, info};
-use pos58_usb::POS58USB;
-use std::io::Read;
-use std::str::FromStr;
-use std::sync::mpsc::{Receiver, Sender};
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::backend::{BackendConfig, PrinterBackend, PrinterStatus};
+use crate::barcode::{Barcode, BARCODE_HEIGHT};
+use crate::codepage;
+use crate::documents::{self, DocumentKind};
+use crate::fonts::Fonts;
+use crate::imaging::{self, ImageOptions};
+use crate::layout::{self, Line, Paragraph};
+use crate::markup;
+use crate::paper::PaperLog;
+use crate::preview;
+use crate::queue::{Journal, PrintJob, PrintQueue, Source};
+use crate::textfile::{self, TextKind};
 
 const PRINTER_WELCOME: &str = "Welcome to Discord!\n\n\n\n";
+/// Printed before the rest of a job which was interrupted by the printer
+const JOB_RESUMED: &str = "(continued)";
+
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(10);
 
 const MAX_DOWNLOAD_SIZE: u64 = 1024 * 1024 * 8; // 8MB
+const HTTP_TIMEOUT: Duration = Duration::from_secs(15);
+/// Enough of a file to recognise any image format by
+const SNIFF_BYTES: u64 = 32;
 pub const PRINTER_CHARS_PER_LINE: usize = 32;
 pub const PRINTER_DOTS_PER_LINE: u32 = 384;
+/// Default line spacing in dots
+pub const PRINTER_LINE_DOTS: u32 = 30;
+/// 203 dpi
+pub const PRINTER_DOTS_PER_MM: u32 = 8;
 
 /// Message handling service
 pub struct PrintHandler {
     client: Client,
-    ditherer: Ditherer<'static>,
-    printer: Sender<PrinterMsg>,
+    queue: PrintQueue,
 }
 
-/// Message from discord thread to printer thread
+/// Part of a print job
 pub enum PrinterMsg {
     Image(image::RgbImage),
     Text(String),
+    /// Text with Discord style Markdown
+    Markup(String),
+    Barcode(Barcode),
+}
+
+/// Something wrong with a user's request, which should be explained to them
+#[derive(Debug)]
+pub struct UserError(pub String);
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UserError {}
+
+impl PrinterMsg {
+    /// Estimated length of paper this will use, in dots
+    pub fn length_dots(&self) -> u32 {
+        match self {
+            PrinterMsg::Image(image) => image.height(),
+            PrinterMsg::Text(text) => layout::length_dots(&fit(markup::plain(text))),
+            PrinterMsg::Markup(text) => layout::length_dots(&fit(markup::parse(text))),
+            // Bars and the text below them
+            PrinterMsg::Barcode(_) => BARCODE_HEIGHT + PRINTER_LINE_DOTS,
+        }
+    }
+}
+
+/// Reports changes in printer health
+pub struct StatusMonitor {
+    last: PrinterStatus,
+    sender: Sender<PrinterStatus>,
+}
+
+impl StatusMonitor {
+    pub fn new(sender: Sender<PrinterStatus>) -> Self {
+        Self {
+            last: PrinterStatus::Unknown,
+            sender,
+        }
+    }
+
+    /// Note the current status, announcing it if the printer became healthy or unhealthy
+    pub fn update(&mut self, status: PrinterStatus) {
+        if status.is_healthy() != self.last.is_healthy() {
+            match status.is_healthy() {
+                true => info!("Printer status: {:?}", status),
+                false => error!("Printer status: {:?}", status),
+            }
+            // Nobody listening is fine
+            let _ = self.sender.send(status);
+        }
+        self.last = status;
+    }
 }
 
 /// Printer thread is seperate from Discord thread to prevent blockage
-pub fn printer_thread(receiver: &mut Receiver<PrinterMsg>) -> Result<()> {
+pub fn printer_thread(
+    backend: &BackendConfig,
+    fonts: &Fonts,
+    journal: &Journal,
+    paper: &Mutex<PaperLog>,
+    monitor: &mut StatusMonitor,
+    receiver: &mut Receiver<u64>,
+) -> Result<()> {
     info!("Starting printer thread...");
 
     // Device init
-    let mut usb_context = libusb::Context::new().context("Failed to create LibUSB context.")?;
-    let mut device = POS58USB::new(&mut usb_context, std::time::Duration::from_secs(1))
-        .context("Failed to connect to printer")?;
-    let mut printer = Printer::new(&mut device, None, None);
+    let mut usb_context = None;
+    let mut device = backend.open(&mut usb_context)?;
+    info!("Printing to {}", device.describe());
+    monitor.update(device.status()?);
 
     // Welcome message
-    printer
+    Printer::new(&mut *device, None, None)
         .chain_align("ct")?
         .chain_println(PRINTER_WELCOME)?
         .flush()?;
+    let welcome_dots = PrinterMsg::Text(PRINTER_WELCOME.into()).length_dots();
+    crate::log_result(paper.lock().unwrap().record("printer", "welcome", welcome_dots));
+
+    // Jobs left over from a previous run, or from before the printer failed
+    let pending = journal.pending()?;
+    if !pending.is_empty() {
+        info!("Replaying {} pending jobs", pending.len());
+    }
+    for id in pending {
+        print_job(&mut *device, fonts, journal, paper, monitor, id)?;
+    }
 
     // Main print loop
     info!("Printer thread initialized!");
-    while let Ok(msg) = receiver.recv() {
-        match msg {
-            PrinterMsg::Image(image) => {
-                let image = EscImage::from(image::DynamicImage::ImageRgb8(image));
-                printer
-                    .chain_align("ct")?
-                    .chain_bit_image(&image, None)?
-                    .flush()?;
-                }
-            PrinterMsg::Text(text) => {
-                printer.chain_align("lt")?.chain_println(&text)?.flush()?;
-            }
+    loop {
+        match receiver.recv_timeout(STATUS_POLL_INTERVAL) {
+            Ok(id) => print_job(&mut *device, fonts, journal, paper, monitor, id)?,
+            // Keep an eye on the printer while idle, so people hear about problems early
+            Err(RecvTimeoutError::Timeout) => monitor.update(device.status()?),
+            Err(RecvTimeoutError::Disconnected) => break,
         }
     }
 
     Err(anyhow!("Printer thread stopped, restarting."))
 }
 
+/// Block until the printer is able to print. Jobs submitted meanwhile wait in the journal.
+fn wait_until_healthy(device: &mut dyn PrinterBackend, monitor: &mut StatusMonitor) -> Result<()> {
+    loop {
+        let status = device.status()?;
+        monitor.update(status);
+        if status.is_healthy() {
+            return Ok(());
+        }
+        thread::sleep(STATUS_POLL_INTERVAL);
+    }
+}
+
+/// Print a job from the journal, marking it done once it has been flushed to the printer
+fn print_job(
+    device: &mut dyn PrinterBackend,
+    fonts: &Fonts,
+    journal: &Journal,
+    paper: &Mutex<PaperLog>,
+    monitor: &mut StatusMonitor,
+    id: u64,
+) -> Result<()> {
+    let job = match journal.load(id) {
+        Ok(Some(job)) => job,
+        // Already printed while replaying
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            // A job that can't be loaded would block the queue forever
+            error!("Discarding job {}: {:#}", id, e);
+            return journal.complete(id);
+        }
+    };
+
+    info!(
+        "Printing job {} from {} ({})",
+        id,
+        job.author,
+        job.source.as_str()
+    );
+    // If the paper runs out (or similar) part way through, carry on from the message that was
+    // cut short once it's fixed, rather than printing the whole job again
+    let mut done = 0;
+    while done < job.msgs.len() {
+        wait_until_healthy(device, monitor)?;
+        if done > 0 {
+            info!("Resuming job {} at message {}", id, done + 1);
+            send_msg(device, fonts, &PrinterMsg::Text(JOB_RESUMED.into()))?;
+        }
+        for msg in &job.msgs[done..] {
+            send_msg(device, fonts, msg)?;
+            let status = device.status()?;
+            monitor.update(status);
+            if !status.is_healthy() {
+                break;
+            }
+            done += 1;
+        }
+    }
+
+    journal.complete(id)?;
+
+    // Accounting is not worth stopping the printer over
+    let dots = job.msgs.iter().map(PrinterMsg::length_dots).sum();
+    crate::log_result(
+        paper
+            .lock()
+            .unwrap()
+            .record(job.source.as_str(), &job.author, dots),
+    );
+    Ok(())
+}
+
+/// Wrap text to the width of the paper
+fn fit(mut paragraphs: Vec<Paragraph>) -> Vec<Line> {
+    for span in paragraphs.iter_mut().flat_map(|p| &mut p.spans) {
+        span.text = codepage::spell_out(&span.text);
+    }
+    layout::layout(&paragraphs, PRINTER_CHARS_PER_LINE)
+}
+
+/// Send one message of a job to the printer
+fn send_msg(device: &mut dyn PrinterBackend, fonts: &Fonts, msg: &PrinterMsg) -> Result<()> {
+    let native_barcodes = device.native_barcodes();
+    match msg {
+        PrinterMsg::Image(image) => {
+            let image = EscImage::from(image::DynamicImage::ImageRgb8(image.clone()));
+            Printer::new(&mut *device, None, None)
+                .chain_align("ct")?
+                .chain_bit_image(&image, None)?
+                .flush()?;
+        }
+        PrinterMsg::Text(text) => {
+            device.write_all(&fonts.escpos(&fit(markup::plain(text))))?;
+        }
+        PrinterMsg::Markup(text) => {
+            device.write_all(&fonts.escpos(&fit(markup::parse(text))))?;
+        }
+        // escposify has no Code128 support, so native barcodes bypass it
+        PrinterMsg::Barcode(barcode) if native_barcodes => device.write_all(&barcode.escpos())?,
+        PrinterMsg::Barcode(barcode) => {
+            let image = EscImage::from(image::DynamicImage::ImageRgb8(barcode.image()));
+            Printer::new(&mut *device, None, None)
+                .chain_align("ct")?
+                .chain_bit_image(&image, None)?
+                .flush()?;
+        }
+    }
+    device.flush()?;
+    Ok(())
+}
+
 impl PrintHandler {
     /// Create a new handler
-    pub fn new(printer: Sender<PrinterMsg>) -> Result<Self> {
+    pub fn new(queue: PrintQueue) -> Result<Self> {
         // Hyper client
         let ssl = NativeTlsClient::new()?;
         let connector = HttpsConnector::new(ssl);
-        let client = hyper::Client::with_connector(connector);
-
-        let ditherer = Ditherer::from_str("floyd")?;
+        let mut client = hyper::Client::with_connector(connector);
+        client.set_read_timeout(Some(HTTP_TIMEOUT));
+        client.set_write_timeout(Some(HTTP_TIMEOUT));
 
-        Ok(Self {
-            client,
-            ditherer,
-            printer,
-        })
+        Ok(Self { client, queue })
     }
 
     /// Handle a printing command
@@ -93,57 +293,123 @@ impl PrintHandler {
             .content
             .trim_start_matches(crate::PRINT_COMMAND)
             .trim_start();
+        let (options, text) =
+            ImageOptions::parse_args(text).map_err(|e| UserError(e.to_string()))?;
         if text.is_empty() && message.attachments.is_empty() {
             return Ok(());
         }
 
+        let author = format!("{}#{}", message.author.name, message.author.discriminator);
+        info!("Handling a new message from {}", author);
+        let mut msgs = Vec::new();
+
         // Message header
         if header {
-            let author = message.author.name;
-            let date = message.timestamp.format("%m/%d/%y %H:%M");
-            info!(
-                "Handling a new message from {}#{}",
-                author, message.author.discriminator
-            );
-            let full_date = format!("{} {}:", author, date);
-            let header = match full_date.chars().count() > PRINTER_CHARS_PER_LINE {
-                true => format!("{}: ", author),
-                false => full_date,
-            };
-
-            self.print_text(header);
+            msgs.push(message_header(&message));
         }
 
         // Message body printing
         if !text.is_empty() {
-            match validate_url(text) {
-                Some(url) => self.print_image(url)?,
-                None => self.print_text(text.into()),
+            match parse_url(text) {
+                Some(url) => match sniff_image(&self.client, url.clone()) {
+                    Some(url) => msgs.push(PrinterMsg::Image(self.download_image(url, &options)?)),
+                    None => match self.link_preview(url, &options) {
+                        Ok(card) => msgs.extend(card),
+                        Err(e) => {
+                            info!("No link preview: {:#}", e);
+                            msgs.push(PrinterMsg::Markup(custom_emoji_names(text)));
+                        }
+                    },
+                },
+                None => msgs.push(PrinterMsg::Markup(custom_emoji_names(text))),
             }
         }
 
-        // Image printing
+        // Attachment printing
         for att in message.attachments {
-            if att.dimensions().is_some() {
+            if let Some(kind) = DocumentKind::from_file_name(&att.filename) {
+                let url = Url::parse(&att.url)?;
+                let pages = self.download_document(url, kind, &options)?;
+                msgs.extend(pages.into_iter().map(PrinterMsg::Image));
+            } else if let Some(kind) = TextKind::from_file_name(&att.filename) {
+                let text = self.download_text(Url::parse(&att.url)?)?;
+                let text = textfile::decode(&text).map_err(|e| UserError(e.to_string()))?;
+                msgs.push(match kind {
+                    TextKind::Plain => PrinterMsg::Text(text),
+                    TextKind::Markdown => PrinterMsg::Markup(text),
+                    TextKind::Code => {
+                        PrinterMsg::Text(textfile::number_lines(&text, PRINTER_CHARS_PER_LINE))
+                    }
+                });
+            } else if att.dimensions().is_some() {
                 if let Some(url) = validate_url(&att.url) {
-                    self.print_image(url)?;
+                    msgs.push(PrinterMsg::Image(self.download_image(url, &options)?));
                 }
             }
         }
-        Ok(())
+
+        self.queue.submit(PrintJob {
+            source: Source::Discord,
+            author,
+            author_id: message.author.id.0.to_string(),
+            msgs,
+        })
     }
 
-    /// Print some text
-    fn print_text(&self, text: String) {
-        crate::fatal_error(
-            self.printer
-                .send(PrinterMsg::Text(text))
-                .context("Printer thread died"),
-        );
+    /// Handle a QR code command
+    pub fn handle_qr(&mut self, message: Message, header: bool) -> Result<()> {
+        let text = message
+            .content
+            .trim_start_matches(crate::QR_COMMAND)
+            .trim();
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        let image = crate::qr::qr_image(text).map_err(|e| UserError(e.to_string()))?;
+        self.submit_single(&message, header, PrinterMsg::Image(image))
+    }
+
+    /// Handle a barcode command
+    pub fn handle_barcode(&mut self, message: Message, header: bool) -> Result<()> {
+        let mut args = message
+            .content
+            .trim_start_matches(crate::BARCODE_COMMAND)
+            .trim()
+            .splitn(2, ' ');
+        let barcode = match (args.next(), args.next()) {
+            (Some(kind), Some(data)) => kind
+                .parse()
+                .and_then(|kind| Barcode::new(kind, data.trim())),
+            _ => Err(anyhow!(
+                "Usage: `{} <code128|ean13|upca> <data>`",
+                crate::BARCODE_COMMAND
+            )),
+        };
+        let barcode = barcode.map_err(|e| UserError(e.to_string()))?;
+        self.submit_single(&message, header, PrinterMsg::Barcode(barcode))
+    }
+
+    /// Print one message on behalf of its author, with the header if enabled
+    fn submit_single(&self, message: &Message, header: bool, msg: PrinterMsg) -> Result<()> {
+        let author = format!("{}#{}", message.author.name, message.author.discriminator);
+        info!("Handling a new message from {}", author);
+        let mut msgs = Vec::new();
+        if header {
+            msgs.push(message_header(message));
+        }
+        msgs.push(msg);
+
+        self.queue.submit(PrintJob {
+            source: Source::Discord,
+            author,
+            author_id: message.author.id.0.to_string(),
+            msgs,
+        })
     }
 
-    /// Download and print some image
-    fn print_image(&self, url: Url) -> Result<()> {
+    /// Download an image and dither it for the printer
+    fn download_image(&self, url: Url, options: &ImageOptions) -> Result<image::RgbImage> {
         // Download the image
         let image = self
             .client
@@ -151,61 +417,180 @@ impl PrintHandler {
             .send()
             .context("Image download failed")?;
 
-        // Read the image into local memory
+        let buf = read_limited(image).context("Image read failed")?;
+        imaging::load(&buf, options)
+    }
+
+    /// Download an SVG or PDF and dither each page for the printer
+    fn download_document(
+        &self,
+        url: Url,
+        kind: DocumentKind,
+        options: &ImageOptions,
+    ) -> Result<Vec<image::RgbImage>> {
+        let document = self
+            .client
+            .get(url)
+            .send()
+            .context("Document download failed")?;
+        let buf = read_limited(document).context("Document read failed")?;
+
+        let pages = match kind {
+            DocumentKind::Svg => vec![documents::render_svg(&buf)?],
+            DocumentKind::Pdf => documents::render_pdf(&buf)?,
+        };
+        Ok(pages
+            .iter()
+            .map(|page| imaging::prepare(page, options))
+            .collect())
+    }
+
+    /// Download a text file, stopping just past the longest one printed
+    fn download_text(&self, url: Url) -> Result<Vec<u8>> {
+        let text = self
+            .client
+            .get(url)
+            .send()
+            .context("Text download failed")?;
         let mut buf = Vec::new();
-        image
-            .take(MAX_DOWNLOAD_SIZE)
+        text.take(textfile::MAX_TEXT_SIZE + 1)
             .read_to_end(&mut buf)
-            .context("Image read failed")?;
-        if buf.len() as u64 == MAX_DOWNLOAD_SIZE {
-            error!(
-                "Attachment size reached maximum download size, {} bytes",
-                MAX_DOWNLOAD_SIZE
-            );
-        }
+            .context("Text read failed")?;
+        Ok(buf)
+    }
 
-        // Decode the image
-        let image = image::load_from_memory(&buf).context("Image parse failed")?;
+    /// A card for a web page: site name, title, description, its picture and a QR code of the
+    /// link
+    fn link_preview(&self, url: Url, options: &ImageOptions) -> Result<Vec<PrinterMsg>> {
+        let (page_url, preview) = preview::fetch(&self.client, url.clone())?;
 
-        // Resize to fit the printer
-        let image = image.resize(
-            PRINTER_DOTS_PER_LINE,
-            9000,
-            image::imageops::FilterType::Triangle,
+        let mut msgs = Vec::new();
+        if let Some(site_name) = preview.site_name {
+            msgs.push(PrinterMsg::Text(site_name));
+        }
+        if let Some(title) = preview.title {
+            let title = format!("### {}", markup::escape(&title));
+            msgs.push(PrinterMsg::Markup(title));
+        }
+        if let Some(description) = preview.description {
+            msgs.push(PrinterMsg::Text(description));
+        }
+        let image = preview.image.and_then(|image| page_url.join(&image).ok());
+        if let Some(image) = image {
+            match self.download_image(image, options) {
+                Ok(image) => msgs.push(PrinterMsg::Image(image)),
+                Err(e) => info!("Preview image for {} failed: {:#}", url, e),
+            }
+        }
+        match crate::qr::qr_image(url.as_str()) {
+            Ok(qr) => msgs.push(PrinterMsg::Image(qr)),
+            Err(_) => msgs.push(PrinterMsg::Text(url.to_string())),
+        }
+        Ok(msgs)
+    }
+}
+
+/// Read a response into memory, up to `MAX_DOWNLOAD_SIZE`
+pub fn read_limited(res: Response) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    res.take(MAX_DOWNLOAD_SIZE).read_to_end(&mut buf)?;
+    if buf.len() as u64 == MAX_DOWNLOAD_SIZE {
+        error!(
+            "Download reached maximum download size, {} bytes",
+            MAX_DOWNLOAD_SIZE
         );
+    }
+    Ok(buf)
+}
 
-        // Convert to the ditherer's image format
-        let image: Img<RGB<f64>> = Img::new(
-            image.to_rgb8().pixels().map(|p| RGB::from(p.0)),
-            image.width(),
-        )
-        .context("Image convert failed")?;
+/// Author and date, printed before a message
+fn message_header(message: &Message) -> PrinterMsg {
+    let date = message.timestamp.format("%m/%d/%y %H:%M");
+    let full_date = format!("{} {}:", message.author.name, date);
+    let header = match full_date.chars().count() > PRINTER_CHARS_PER_LINE {
+        true => format!("{}: ", message.author.name),
+        false => full_date,
+    };
+    PrinterMsg::Text(header)
+}
 
-        // Dither the image
-        let quantize = dither::create_quantize_n_bits_func(1)?;
-        let image = image.convert_with(|rgb| rgb.to_chroma_corrected_black_and_white());
-        let image = self
-            .ditherer
-            .dither(image, quantize)
-            .convert_with(RGB::from_chroma_corrected_black_and_white);
-
-        // Convert image back to normal...
-        let (width, height) = image.size();
-        let image = image::RgbImage::from_raw(width, height, image.raw_buf())
-            .context("Could not convert back to a regular image")?;
-
-        // Send image to the printer thread
-        crate::fatal_error(
-            self.printer
-                .send(PrinterMsg::Image(image))
-                .context("Printer thread died"),
-        );
+/// Replace Discord's custom emoji, `<:name:id>` or `<a:name:id>` when animated, with `:name:`
+fn custom_emoji_names(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
 
-        Ok(())
+        let emoji = rest.find('>').and_then(|end| {
+            let inner = &rest[1..end];
+            let inner = inner.strip_prefix('a').unwrap_or(inner);
+            let mut parts = inner.strip_prefix(':')?.split(':');
+            let name = parts.next().filter(|n| !n.is_empty())?;
+            let id = parts.next().filter(|id| id.bytes().all(|b| b.is_ascii_digit()))?;
+            match parts.next().is_none() && !id.is_empty() {
+                true => Some((name, end)),
+                false => None,
+            }
+        });
+        match emoji {
+            Some((name, end)) => {
+                out.push_str(&format!(":{}:", name));
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// A message which is nothing but a web link
+fn parse_url(text: &str) -> Option<Url> {
+    let url = Url::parse(text.trim()).ok()?;
+    match url.scheme() {
+        "http" | "https" => Some(url),
+        _ => None,
     }
 }
 
-/// Check if this is a valid image URL
+/// Find out whether a URL is an image by asking the server, following any redirects. Returns
+/// where the image ended up.
+fn sniff_image(client: &Client, url: Url) -> Option<Url> {
+    // Plenty of servers refuse HEAD, or call everything application/octet-stream, so unless they
+    // are sure it's something else, look at the first few bytes too
+    if let Ok(res) = client.head(url.clone()).send() {
+        if res.status.is_success() {
+            match res.headers.get::<ContentType>() {
+                Some(ContentType(Mime(TopLevel::Image, _, _))) => return Some(res.url.clone()),
+                Some(ContentType(Mime(TopLevel::Application, SubLevel::OctetStream, _))) => {}
+                None => {}
+                Some(_) => return None,
+            }
+        }
+    }
+
+    let res = client
+        .get(url)
+        .header(Range::bytes(0, SNIFF_BYTES - 1))
+        .send()
+        .ok()?;
+    if !res.status.is_success() {
+        return None;
+    }
+    let url = res.url.clone();
+    if let Some(ContentType(Mime(TopLevel::Image, _, _))) = res.headers.get::<ContentType>() {
+        return Some(url);
+    }
+    let mut head = Vec::new();
+    res.take(SNIFF_BYTES).read_to_end(&mut head).ok()?;
+    image::guess_format(&head).ok().map(|_| url)
+}
+
+/// Check if this is a valid image URL, going by the file extension
 fn validate_url(s: impl IntoUrl) -> Option<Url> {
     let url = s.into_url().ok()?;
     let file_name = url.path_segments()?.last()?;
@@ -219,6 +604,64 @@ fn validate_url(s: impl IntoUrl) -> Option<Url> {
     }
 }
 
+/// Serve a few canned responses over HTTP on a local port, returning its URL
+#[cfg(test)]
+pub fn test_server() -> String {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const GIF: &[u8] = b"GIF89a\x01\0\x01\0";
+    const ARTICLE: &[u8] =
+        b"<html><head><meta property=\"og:title\" content=\"Fish &amp; chips\">\
+        <meta property=\"og:image\" content=\"photo\"></head></html>";
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            // Skip the headers
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                line.clear();
+            }
+            let mut parts = request.split(' ');
+            let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+            let (status, headers, body): (&str, &str, &[u8]) = match (method, path) {
+                (_, "/photo") | (_, "/photo.webp?width=300") => {
+                    ("200 OK", "Content-Type: image/png\r\n", PNG)
+                }
+                (_, "/moved") => ("302 Found", "Location: /photo\r\n", b""),
+                ("HEAD", "/blob") => ("405 Method Not Allowed", "", b""),
+                (_, "/blob") => ("200 OK", "Content-Type: application/octet-stream\r\n", GIF),
+                (_, "/page.png") => ("200 OK", "Content-Type: text/html\r\n", b"<html></html>"),
+                (_, "/article") => (
+                    "200 OK",
+                    "Content-Type: text/html; charset=utf-8\r\n",
+                    ARTICLE,
+                ),
+                _ => ("404 Not Found", "", b""),
+            };
+            let head = format!(
+                "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                headers,
+                body.len()
+            );
+            let _ = stream.write_all(head.as_bytes());
+            if method != "HEAD" {
+                let _ = stream.write_all(body);
+            }
+        }
+    });
+    format!("http://{}", addr)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -233,4 +676,31 @@ mod tests {
         assert_eq!(validate_url("https://fuck.com/wat.html"), None);
         assert_eq!(validate_url("wat.png"), None);
     }
+
+    #[test]
+    fn test_sniff_image() {
+        assert_eq!(parse_url("look at https://fuck.com"), None);
+        assert_eq!(parse_url("ftp://fuck.com/wat.png"), None);
+
+        // Images are recognised by what the server says they are, not their names
+        let server = test_server();
+        let client = Client::new();
+        let sniff = |path: &str| sniff_image(&client, parse_url(&format!("{}{}", server, path))?);
+        let url = |path: &str| Url::parse(&format!("{}{}", server, path)).ok();
+        assert_eq!(sniff("/photo"), url("/photo"));
+        assert_eq!(sniff("/photo.webp?width=300"), url("/photo.webp?width=300"));
+        assert_eq!(sniff("/moved"), url("/photo"));
+        assert_eq!(sniff("/blob"), url("/blob"));
+        assert_eq!(sniff("/page.png"), None);
+        assert_eq!(sniff("/missing.png"), None);
+    }
+
+    #[test]
+    fn test_custom_emoji_names() {
+        assert_eq!(
+            custom_emoji_names("hi <:wave:123> <a:party:45>!"),
+            "hi :wave: :party:!"
+        );
+        assert_eq!(custom_emoji_names("1 < 2 <b> <:x:y>"), "1 < 2 <b> <:x:y>");
+    }
 }
@@ -0,0 +1,55 @@
+use anyhow::{ensure, Context, Result};
+use image::{Rgb, RgbImage};
+use qrcode::{Color, QrCode};
+
+use crate::printer::PRINTER_DOTS_PER_LINE;
+
+/// Blank modules around the code, as required by the spec for reliable scanning
+const QUIET_ZONE: u32 = 4;
+/// Largest module size in dots. Bigger codes just waste paper.
+const MAX_MODULE_DOTS: u32 = 6;
+
+/// Render text as a QR code, scaled up as far as reasonable to fit the paper
+pub fn qr_image(text: &str) -> Result<RgbImage> {
+    let code = QrCode::new(text.as_bytes()).context("Text doesn't fit in a QR code")?;
+    let width = code.width() as u32;
+    let colors = code.to_colors();
+
+    let modules = width + 2 * QUIET_ZONE;
+    let scale = (PRINTER_DOTS_PER_LINE / modules).min(MAX_MODULE_DOTS);
+    ensure!(scale > 0, "Text is too long to print as a QR code");
+
+    let size = modules * scale;
+    Ok(RgbImage::from_fn(size, size, |x, y| {
+        let (mx, my) = (x / scale, y / scale);
+        let dark = (QUIET_ZONE..QUIET_ZONE + width).contains(&mx)
+            && (QUIET_ZONE..QUIET_ZONE + width).contains(&my)
+            && colors[((my - QUIET_ZONE) * width + mx - QUIET_ZONE) as usize] == Color::Dark;
+        match dark {
+            true => Rgb([0x00; 3]),
+            false => Rgb([0xFF; 3]),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qr_image() {
+        let text = "https://discord.com/channels/1/2/3";
+        let image = qr_image(text).unwrap();
+        assert_eq!(image.width(), image.height());
+        assert!(image.width() <= PRINTER_DOTS_PER_LINE);
+
+        // Quiet zone, then the corner of the top left finder pattern
+        let modules = QrCode::new(text).unwrap().width() as u32 + 2 * QUIET_ZONE;
+        let scale = image.width() / modules;
+        assert_eq!(image.get_pixel(0, 0), &Rgb([0xFF; 3]));
+        let corner = QUIET_ZONE * scale;
+        assert_eq!(image.get_pixel(corner, corner), &Rgb([0x00; 3]));
+
+        assert!(qr_image(&"x".repeat(5000)).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use anyhow::{bail, format_err, Context, Result};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use crate::barcode::Barcode;
+use crate::printer::PrinterMsg;
+use crate::quota::Quotas;
+
+const JOB_FILE: &str = "job.txt";
+
+/// Frontend a job was submitted through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    Discord,
+    Lua,
+    Twitter,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Discord => "discord",
+            Source::Lua => "lua",
+            Source::Twitter => "twitter",
+        }
+    }
+
+    /// Platform the author's id belongs to. Lua scripts are sent through Discord.
+    pub fn platform(&self) -> &'static str {
+        match self {
+            Source::Discord | Source::Lua => "discord",
+            Source::Twitter => "twitter",
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "discord" => Ok(Source::Discord),
+            "lua" => Ok(Source::Lua),
+            "twitter" => Ok(Source::Twitter),
+            _ => Err(format_err!("Unknown job source {}", s)),
+        }
+    }
+}
+
+/// Everything printed in response to a single request
+pub struct PrintJob {
+    pub source: Source,
+    /// Display name of the author
+    pub author: String,
+    /// Platform specific id of the author
+    pub author_id: String,
+    pub msgs: Vec<PrinterMsg>,
+}
+
+/// Pending jobs on disk. Each job is a directory named after its id, holding a `job.txt`
+/// describing it and a PNG for each image. Jobs are deleted once they have been printed.
+pub struct Journal {
+    dir: PathBuf,
+    next_id: AtomicU64,
+}
+
+impl Journal {
+    /// Open (creating if necessary) the journal directory
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+
+        // Clean up jobs that were never completely written
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "tmp") {
+                fs::remove_dir_all(&path)?;
+            }
+        }
+
+        let journal = Self {
+            dir,
+            next_id: AtomicU64::new(0),
+        };
+        let next_id = journal.pending()?.last().map_or(0, |id| id + 1);
+        journal.next_id.store(next_id, Ordering::SeqCst);
+        Ok(journal)
+    }
+
+    fn job_dir(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:016}", id))
+    }
+
+    /// Durably record a job, synced to disk before it's made visible, returning its id
+    pub fn record(&self, job: &PrintJob) -> Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+
+        // Write everything to a temporary directory first, so a crash never leaves half a job behind
+        let tmp = self.job_dir(id).with_extension("tmp");
+        fs::create_dir_all(&tmp)?;
+
+        let mut text = String::new();
+        text.push_str(&format!("source {}\n", job.source.as_str()));
+        text.push_str(&format!("author {}\n", escape(&job.author)));
+        text.push_str(&format!("author_id {}\n", escape(&job.author_id)));
+        for (idx, msg) in job.msgs.iter().enumerate() {
+            match msg {
+                PrinterMsg::Text(t) => text.push_str(&format!("text {}\n", escape(t))),
+                PrinterMsg::Markup(t) => text.push_str(&format!("markup {}\n", escape(t))),
+                PrinterMsg::Image(image) => {
+                    let name = format!("{}.png", idx);
+                    image
+                        .save(tmp.join(&name))
+                        .context("Failed to save image to journal")?;
+                    sync(&tmp.join(&name))?;
+                    text.push_str(&format!("image {}\n", name));
+                }
+                PrinterMsg::Barcode(barcode) => text.push_str(&format!(
+                    "barcode {} {}\n",
+                    barcode.symbology().as_str(),
+                    escape(barcode.data())
+                )),
+            }
+        }
+        fs::write(tmp.join(JOB_FILE), text)?;
+        sync(&tmp.join(JOB_FILE))?;
+        sync(&tmp)?;
+
+        // Only once the contents are on disk, or a power cut could leave an empty job
+        fs::rename(&tmp, self.job_dir(id))?;
+        sync(&self.dir)?;
+
+        Ok(id)
+    }
+
+    /// Ids of jobs which have not been printed yet, oldest first
+    pub fn pending(&self) -> Result<Vec<u64>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some() {
+                continue;
+            }
+            if let Some(id) = path.file_name().and_then(|n| n.to_str()?.parse().ok()) {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Load a job, or None if it has already been printed
+    pub fn load(&self, id: u64) -> Result<Option<PrintJob>> {
+        let dir = self.job_dir(id);
+        if !dir.exists() {
+            return Ok(None);
+        }
+
+        let text = fs::read_to_string(dir.join(JOB_FILE))
+            .with_context(|| format!("Failed to read job {}", id))?;
+
+        let mut source = None;
+        let mut author = None;
+        let mut author_id = None;
+        let mut msgs = Vec::new();
+        for line in text.lines() {
+            let (key, value) = match line.find(' ') {
+                Some(idx) => (&line[..idx], &line[idx + 1..]),
+                None => (line, ""),
+            };
+            match key {
+                "source" => source = Some(value.parse()?),
+                "author" => author = Some(unescape(value)),
+                "author_id" => author_id = Some(unescape(value)),
+                "text" => msgs.push(PrinterMsg::Text(unescape(value))),
+                "markup" => msgs.push(PrinterMsg::Markup(unescape(value))),
+                "image" => {
+                    let image = image::open(dir.join(value))
+                        .with_context(|| format!("Failed to load image {} of job {}", value, id))?;
+                    msgs.push(PrinterMsg::Image(image.to_rgb8()));
+                }
+                "barcode" => {
+                    let mut parts = value.splitn(2, ' ');
+                    let symbology = parts.next().unwrap_or("").parse()?;
+                    let data = unescape(parts.next().unwrap_or(""));
+                    msgs.push(PrinterMsg::Barcode(Barcode::new(symbology, &data)?));
+                }
+                _ => bail!("Unknown line in job {}: {}", id, line),
+            }
+        }
+
+        let missing = || format_err!("Job {} is missing its header", id);
+        Ok(Some(PrintJob {
+            source: source.ok_or_else(missing)?,
+            author: author.ok_or_else(missing)?,
+            author_id: author_id.ok_or_else(missing)?,
+            msgs,
+        }))
+    }
+
+    /// Forget a job after it has been printed
+    pub fn complete(&self, id: u64) -> Result<()> {
+        fs::remove_dir_all(self.job_dir(id)).with_context(|| format!("Failed to complete job {}", id))
+    }
+}
+
+/// Flush a file or directory to disk
+fn sync(path: &Path) -> Result<()> {
+    File::open(path)
+        .and_then(|f| f.sync_all())
+        .with_context(|| format!("Failed to sync {:?}", path))
+}
+
+/// Handle used by the frontends to submit jobs to the printer thread
+#[derive(Clone)]
+pub struct PrintQueue {
+    journal: Arc<Journal>,
+    sender: Sender<u64>,
+    quotas: Arc<Mutex<Quotas>>,
+}
+
+impl PrintQueue {
+    pub fn new(journal: Arc<Journal>, sender: Sender<u64>, quotas: Quotas) -> Self {
+        Self {
+            journal,
+            sender,
+            quotas: Arc::new(Mutex::new(quotas)),
+        }
+    }
+
+    /// Record a job and wake up the printer thread.
+    /// Fails with a `QuotaExceeded` if the author has used too much paper recently.
+    pub fn submit(&self, job: PrintJob) -> Result<()> {
+        if job.msgs.is_empty() {
+            return Ok(());
+        }
+
+        let dots = job.msgs.iter().map(PrinterMsg::length_dots).sum();
+        let now = chrono::Local::now();
+        let mut quotas = self.quotas.lock().unwrap();
+        quotas.charge(job.source.platform(), &job.author_id, dots, now)?;
+
+        // Paper that's never printed shouldn't count against anyone
+        let id = match self.journal.record(&job) {
+            Ok(id) => id,
+            Err(e) => {
+                quotas.refund(job.source.platform(), &job.author_id, now);
+                return Err(e.context("Failed to record print job"));
+            }
+        };
+        drop(quotas);
+        self.sender.send(id).context("Printer thread died")
+    }
+}
+
+/// Escape a string so that it fits on one line
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::barcode::Symbology;
+
+    #[test]
+    fn test_journal() {
+        let dir = std::env::temp_dir().join(format!("print_bot_journal_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let journal = Journal::open(&dir).unwrap();
+        let text = "Hello\\world\nsecond line";
+        let job = PrintJob {
+            source: Source::Discord,
+            author: "someone#1234".into(),
+            author_id: "42".into(),
+            msgs: vec![
+                PrinterMsg::Text(text.into()),
+                PrinterMsg::Image(image::RgbImage::new(384, 2)),
+                PrinterMsg::Barcode(Barcode::new(Symbology::Code128, "a b").unwrap()),
+            ],
+        };
+        let first = journal.record(&job).unwrap();
+        let second = journal.record(&job).unwrap();
+        assert_eq!(journal.pending().unwrap(), vec![first, second]);
+
+        let loaded = journal.load(first).unwrap().unwrap();
+        assert_eq!(loaded.source, Source::Discord);
+        assert_eq!(loaded.author, "someone#1234");
+        assert!(matches!(&loaded.msgs[0], PrinterMsg::Text(t) if t == text));
+        assert!(matches!(&loaded.msgs[1], PrinterMsg::Image(i) if i.height() == 2));
+        assert!(matches!(&loaded.msgs[2], PrinterMsg::Barcode(b) if b.data() == "a b"));
+
+        // Pending jobs survive a restart, and ids keep increasing
+        journal.complete(first).unwrap();
+        assert!(journal.load(first).unwrap().is_none());
+        let journal = Journal::open(&dir).unwrap();
+        assert_eq!(journal.pending().unwrap(), vec![second]);
+        assert!(journal.record(&job).unwrap() > second);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+use chrono::{DateTime, Duration, Local};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::printer::PRINTER_DOTS_PER_MM;
+
+/// Paper budgets applied to every user. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuotaConfig {
+    pub hourly_mm: Option<u32>,
+    pub daily_mm: Option<u32>,
+    pub cooldown: Option<Duration>,
+}
+
+/// Why a job was refused
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    reason: &'static str,
+    /// None if the job could never fit in the budget
+    retry_at: Option<DateTime<Local>>,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.retry_at {
+            Some(t) => write!(
+                f,
+                "Sorry, {}. You can print again at {} (bot-local time).",
+                self.reason,
+                t.format("%H:%M")
+            ),
+            None => write!(f, "Sorry, {}. Try printing something shorter!", self.reason),
+        }
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// When each job was charged, and its length in dots
+type History = Vec<(DateTime<Local>, u32)>;
+
+/// Paper used by each (platform, user id) recently
+pub struct Quotas {
+    config: QuotaConfig,
+    history: HashMap<(&'static str, String), History>,
+}
+
+impl Quotas {
+    pub fn new(config: QuotaConfig) -> Self {
+        Self {
+            config,
+            history: HashMap::new(),
+        }
+    }
+
+    /// Charge a job of `dots` length to a user, or explain when they may print again
+    pub fn charge(
+        &mut self,
+        platform: &'static str,
+        user_id: &str,
+        dots: u32,
+        now: DateTime<Local>,
+    ) -> Result<(), QuotaExceeded> {
+        let history = self
+            .history
+            .entry((platform, user_id.to_string()))
+            .or_default();
+
+        // Nothing older than a day matters
+        history.retain(|(t, _)| now - *t < Duration::days(1));
+
+        if let (Some(cooldown), Some((last, _))) = (self.config.cooldown, history.last()) {
+            if now - *last < cooldown {
+                return Err(QuotaExceeded {
+                    reason: "you're printing too quickly",
+                    retry_at: Some(*last + cooldown),
+                });
+            }
+        }
+
+        let windows = [
+            (self.config.hourly_mm, Duration::hours(1), "you've used up your paper for this hour"),
+            (self.config.daily_mm, Duration::days(1), "you've used up your paper for today"),
+        ];
+        for &(limit_mm, window, reason) in &windows {
+            if let Some(limit_mm) = limit_mm {
+                let limit = limit_mm * PRINTER_DOTS_PER_MM;
+                if dots > limit {
+                    return Err(QuotaExceeded {
+                        reason: "that's longer than the paper limit",
+                        retry_at: None,
+                    });
+                }
+                if let Some(retry_at) = window_retry(history, now, window, limit - dots) {
+                    return Err(QuotaExceeded {
+                        reason,
+                        retry_at: Some(retry_at),
+                    });
+                }
+            }
+        }
+
+        history.push((now, dots));
+        Ok(())
+    }
+
+    /// Undo a charge made at `time`
+    pub fn refund(&mut self, platform: &'static str, user_id: &str, time: DateTime<Local>) {
+        if let Some(history) = self.history.get_mut(&(platform, user_id.to_string())) {
+            if let Some(idx) = history.iter().rposition(|(t, _)| *t == time) {
+                history.remove(idx);
+            }
+        }
+    }
+}
+
+/// When enough of the usage within `window` will have expired to leave at most `allowed` dots
+fn window_retry(
+    history: &[(DateTime<Local>, u32)],
+    now: DateTime<Local>,
+    window: Duration,
+    allowed: u32,
+) -> Option<DateTime<Local>> {
+    let recent: Vec<_> = history.iter().filter(|(t, _)| now - *t < window).collect();
+    let mut used: u32 = recent.iter().map(|(_, d)| d).sum();
+    if used <= allowed {
+        return None;
+    }
+
+    // History is in chronological order, so the oldest entries expire first
+    for (t, dots) in recent {
+        used -= dots;
+        if used <= allowed {
+            return Some(*t + window);
+        }
+    }
+    unreachable!("Usage of zero always fits")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_quotas() {
+        let mm = PRINTER_DOTS_PER_MM;
+        let mut quotas = Quotas::new(QuotaConfig {
+            hourly_mm: Some(100),
+            daily_mm: Some(150),
+            cooldown: Some(Duration::seconds(30)),
+        });
+        let start = Local.with_ymd_and_hms(2021, 3, 1, 12, 0, 0).unwrap();
+        let at = |minutes| start + Duration::minutes(minutes);
+
+        assert!(quotas.charge("discord", "1", 60 * mm, at(0)).is_ok());
+
+        // Cooldown
+        let err = quotas.charge("discord", "1", mm, start + Duration::seconds(10));
+        assert_eq!(err.unwrap_err().retry_at, Some(start + Duration::seconds(30)));
+
+        // Other users and platforms are independent
+        assert!(quotas.charge("discord", "2", mm, at(0)).is_ok());
+        assert!(quotas.charge("twitter", "1", mm, at(0)).is_ok());
+
+        // Hourly budget frees up when the first job is an hour old
+        assert!(quotas.charge("discord", "1", 30 * mm, at(10)).is_ok());
+        let err = quotas.charge("discord", "1", 20 * mm, at(20));
+        assert_eq!(err.unwrap_err().retry_at, Some(at(60)));
+
+        // Daily budget
+        assert!(quotas.charge("discord", "1", 50 * mm, at(61)).is_ok());
+        let err = quotas.charge("discord", "1", 20 * mm, at(120));
+        assert_eq!(err.unwrap_err().retry_at, Some(at(24 * 60)));
+
+        // Jobs that can never fit
+        let err = quotas.charge("discord", "3", 101 * mm, at(0));
+        assert_eq!(err.unwrap_err().retry_at, None);
+
+        // Refunds give the paper back, cooldown included
+        assert!(quotas.charge("discord", "4", 100 * mm, at(0)).is_ok());
+        quotas.refund("discord", "4", at(0));
+        assert!(quotas.charge("discord", "4", 100 * mm, at(0)).is_ok());
+    }
+}
@@ -0,0 +1,472 @@
+//! Lua scripts run in a worker process, this program started again with `--lua-worker`, so a
+//! crash or runaway script can't take the bot down with it. The worker is locked down with
+//! resource limits and a seccomp filter before it runs anything, and talks to the bot over its
+//! stdin and stdout. Each message is a header line, its kind and the length of each of its
+//! fields, followed by the fields themselves.
+
+use anyhow::{bail, ensure, Context, Result};
+use image::RgbImage;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::barcode::Barcode;
+use crate::imaging::MAX_LENGTH;
+use crate::printer::{PrinterMsg, PRINTER_DOTS_PER_LINE};
+
+/// Largest image message, across all of its fields: a full length image
+const MAX_IMAGE_MSG_SIZE: usize = (PRINTER_DOTS_PER_LINE * MAX_LENGTH * 3) as usize + 1024;
+/// Largest message of any other kind
+const MAX_MSG_SIZE: usize = 1024 * 1024;
+const MAX_HEADER_SIZE: u64 = 256;
+/// How much longer than a script's own time limit the worker gets to finish it
+const WORKER_GRACE: Duration = Duration::from_secs(5);
+/// Room beyond the Lua memory limit for the worker's own allocations, like dithering images
+const MEMORY_HEADROOM: u64 = 256 * 1024 * 1024;
+
+/// Messages between the bot and the worker
+pub enum WorkerMsg {
+    /// Bot to worker: run a script for a user with these stored values
+    Run {
+        user_id: String,
+        values: String,
+        code: String,
+    },
+    /// Worker to bot: something the script printed
+    Print(PrinterMsg),
+    /// Worker to bot: the user's stored values, sent if the script changed them
+    Store(String),
+    /// Worker to bot: the script has finished
+    Done,
+}
+
+pub fn write_msg(w: &mut impl Write, msg: &WorkerMsg) -> Result<()> {
+    let width;
+    let (kind, fields): (&str, Vec<&[u8]>) = match msg {
+        WorkerMsg::Run {
+            user_id,
+            values,
+            code,
+        } => (
+            "run",
+            vec![user_id.as_bytes(), values.as_bytes(), code.as_bytes()],
+        ),
+        WorkerMsg::Print(PrinterMsg::Text(t)) => ("text", vec![t.as_bytes()]),
+        WorkerMsg::Print(PrinterMsg::Markup(t)) => ("markup", vec![t.as_bytes()]),
+        WorkerMsg::Print(PrinterMsg::Image(image)) => {
+            width = image.width().to_string();
+            ("image", vec![width.as_bytes(), image.as_raw()])
+        }
+        WorkerMsg::Print(PrinterMsg::Barcode(barcode)) => (
+            "barcode",
+            vec![
+                barcode.symbology().as_str().as_bytes(),
+                barcode.data().as_bytes(),
+            ],
+        ),
+        WorkerMsg::Store(values) => ("store", vec![values.as_bytes()]),
+        WorkerMsg::Done => ("done", vec![]),
+    };
+
+    let mut header = kind.to_string();
+    for field in &fields {
+        header.push_str(&format!(" {}", field.len()));
+    }
+    writeln!(w, "{}", header)?;
+    for field in fields {
+        w.write_all(field)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// The next message, or None once the other end has hung up
+pub fn read_msg(r: &mut impl BufRead) -> Result<Option<WorkerMsg>> {
+    let mut header = String::new();
+    if r.by_ref().take(MAX_HEADER_SIZE).read_line(&mut header)? == 0 {
+        return Ok(None);
+    }
+    ensure!(header.ends_with('\n'), "Malformed message header");
+    let mut parts = header.split_whitespace();
+    let kind = parts.next().context("Malformed message header")?;
+
+    let max_size = match kind {
+        "image" => MAX_IMAGE_MSG_SIZE,
+        _ => MAX_MSG_SIZE,
+    };
+    let mut total = 0;
+    let mut fields = Vec::new();
+    for len in parts {
+        let len: usize = len.parse().context("Malformed message header")?;
+        total += len;
+        ensure!(total <= max_size, "Message too long");
+        let mut field = vec![0; len];
+        r.read_exact(&mut field)?;
+        fields.push(field);
+    }
+
+    let mut fields = fields.into_iter();
+    let mut next = || -> Result<String> {
+        let field = fields.next().context("Message is missing a field")?;
+        String::from_utf8(field).context("Message isn't UTF-8")
+    };
+    let msg = match kind {
+        "run" => WorkerMsg::Run {
+            user_id: next()?,
+            values: next()?,
+            code: next()?,
+        },
+        "text" => WorkerMsg::Print(PrinterMsg::Text(next()?)),
+        "markup" => WorkerMsg::Print(PrinterMsg::Markup(next()?)),
+        "image" => {
+            let width: u32 = next()?.parse()?;
+            let data = fields.next().context("Message is missing a field")?;
+            let row = width as usize * 3;
+            ensure!(row > 0 && data.len() % row == 0, "Malformed image");
+            let height = (data.len() / row) as u32;
+            let image = RgbImage::from_raw(width, height, data).context("Malformed image")?;
+            WorkerMsg::Print(PrinterMsg::Image(image))
+        }
+        "barcode" => {
+            let symbology = next()?.parse()?;
+            WorkerMsg::Print(PrinterMsg::Barcode(Barcode::new(symbology, &next()?)?))
+        }
+        "store" => WorkerMsg::Store(next()?),
+        "done" => WorkerMsg::Done,
+        _ => bail!("Unknown message {:?}", kind),
+    };
+    Ok(Some(msg))
+}
+
+/// The bot's end of a worker process, which is killed when this is dropped
+pub struct Worker {
+    child: Child,
+    input: ChildStdin,
+    msgs: Receiver<Result<WorkerMsg>>,
+}
+
+impl Worker {
+    /// Start this program again as a worker, with the same options
+    pub fn spawn() -> Result<Self> {
+        let mut child = Command::new(std::env::current_exe()?)
+            .args(std::env::args_os().skip(1))
+            .arg("--lua-worker")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Failed to start the Lua worker")?;
+        let input = child.stdin.take().context("Lua worker has no stdin")?;
+        let output = child.stdout.take().context("Lua worker has no stdout")?;
+
+        // Read on another thread, so a hung worker can be given up on
+        let (sender, msgs) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = BufReader::new(output);
+            loop {
+                let msg = read_msg(&mut output)
+                    .and_then(|msg| msg.context("Lua worker exited"))
+                    .context("Lua worker crashed");
+                let failed = msg.is_err();
+                if sender.send(msg).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self { child, input, msgs })
+    }
+
+    /// Whether the worker is still around to run scripts
+    pub fn is_running(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// Run a script which may take `timeout`, returning what it printed and the user's stored
+    /// values if it changed them. Fails if the worker crashed or hung, and shouldn't be used again.
+    pub fn run(
+        &mut self,
+        user_id: &str,
+        values: String,
+        code: String,
+        timeout: Duration,
+    ) -> Result<(Vec<PrinterMsg>, Option<String>)> {
+        let run = WorkerMsg::Run {
+            user_id: user_id.to_string(),
+            values,
+            code,
+        };
+        write_msg(&mut self.input, &run).context("Lua worker crashed")?;
+
+        let deadline = Instant::now() + timeout + WORKER_GRACE;
+        let mut printed = Vec::new();
+        let mut stored = None;
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            match self.msgs.recv_timeout(wait) {
+                Ok(Ok(WorkerMsg::Print(msg))) => printed.push(msg),
+                Ok(Ok(WorkerMsg::Store(values))) => stored = Some(values),
+                Ok(Ok(WorkerMsg::Done)) => return Ok((printed, stored)),
+                Ok(Ok(WorkerMsg::Run { .. })) => bail!("Lua worker sent a script"),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => bail!("Lua worker stopped responding"),
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Move the worker's pipes to the bot off stdin and stdout, so scripts can't get at them with
+/// the `io` library. Script output ends up on stderr, next to the bot's own.
+pub fn take_pipes() -> Result<(BufReader<File>, File)> {
+    let null = File::open("/dev/null")?;
+    unsafe {
+        let input = libc::dup(0);
+        let output = libc::dup(1);
+        ensure!(
+            input >= 0 && output >= 0,
+            "Failed to take the worker's pipes"
+        );
+        ensure!(
+            libc::dup2(null.as_raw_fd(), 0) >= 0 && libc::dup2(2, 1) >= 0,
+            "Failed to take the worker's pipes"
+        );
+        Ok((
+            BufReader::new(File::from_raw_fd(input)),
+            File::from_raw_fd(output),
+        ))
+    }
+}
+
+/// Lock the worker down before it runs any scripts. It can't create files or processes, or use
+/// much more memory than Lua is allowed, and any system call beyond the few needed to talk to
+/// the bot and allocate memory fails.
+pub fn restrict(max_memory: usize) -> Result<()> {
+    // Memory is limited by address space, on top of what the worker has mapped already
+    let statm = fs::read_to_string("/proc/self/statm")?;
+    let pages: u64 = statm
+        .split_whitespace()
+        .next()
+        .context("Malformed /proc/self/statm")?
+        .parse()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let address_space = pages * page_size + max_memory as u64 + MEMORY_HEADROOM;
+
+    let limits = [
+        (libc::RLIMIT_AS, address_space),
+        (libc::RLIMIT_NPROC, 0),
+        (libc::RLIMIT_CORE, 0),
+    ];
+    for (resource, limit) in limits.iter() {
+        // rlim_t is only 32 bits on some platforms
+        let limit = libc::rlim_t::try_from(*limit).unwrap_or(libc::RLIM_INFINITY);
+        let limit = libc::rlimit {
+            rlim_cur: limit,
+            rlim_max: limit,
+        };
+        ensure!(
+            unsafe { libc::setrlimit(*resource, &limit) } == 0,
+            "Failed to limit the Lua worker: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+
+    // Allow the listed system calls on this architecture, and fail the rest with EPERM
+    let mut filter = vec![
+        SockFilter::load(SECCOMP_DATA_ARCH),
+        SockFilter::jump_eq(AUDIT_ARCH, 1, 0),
+        SockFilter::ret(SECCOMP_RET_KILL_PROCESS),
+        SockFilter::load(SECCOMP_DATA_NR),
+    ];
+    for &nr in ALLOWED_SYSCALLS {
+        filter.push(SockFilter::jump_eq(nr as u32, 0, 1));
+        filter.push(SockFilter::ret(SECCOMP_RET_ALLOW));
+    }
+    filter.push(SockFilter::ret(SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    let program = SockFprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr(),
+    };
+
+    unsafe {
+        ensure!(
+            libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0,
+            "Failed to sandbox the Lua worker: {}",
+            std::io::Error::last_os_error()
+        );
+        ensure!(
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                SECCOMP_MODE_FILTER,
+                &program as *const SockFprog,
+            ) == 0,
+            "Failed to sandbox the Lua worker: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Reading and writing the pipes, memory, clocks and exiting
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_close,
+    libc::SYS_brk,
+    #[cfg(not(target_arch = "arm"))]
+    libc::SYS_mmap,
+    #[cfg(target_arch = "arm")]
+    libc::SYS_mmap2,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_futex,
+    libc::SYS_getrandom,
+    libc::SYS_clock_gettime,
+    // clock_gettime64, which 32 bit ARM uses for 64 bit times
+    #[cfg(target_arch = "arm")]
+    403,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_sigaltstack,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+#[cfg(target_arch = "arm")]
+const AUDIT_ARCH: u32 = 0x4000_0028;
+
+const SECCOMP_MODE_FILTER: libc::c_ulong = 2;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7FFF_0000;
+/// Offsets into `struct seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// A classic BPF instruction, `struct sock_filter`
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+impl SockFilter {
+    fn load(offset: u32) -> Self {
+        // BPF_LD | BPF_W | BPF_ABS
+        Self {
+            code: 0x20,
+            jt: 0,
+            jf: 0,
+            k: offset,
+        }
+    }
+
+    fn jump_eq(value: u32, jt: u8, jf: u8) -> Self {
+        // BPF_JMP | BPF_JEQ | BPF_K
+        Self {
+            code: 0x15,
+            jt,
+            jf,
+            k: value,
+        }
+    }
+
+    fn ret(value: u32) -> Self {
+        // BPF_RET | BPF_K
+        Self {
+            code: 0x06,
+            jt: 0,
+            jf: 0,
+            k: value,
+        }
+    }
+}
+
+/// `struct sock_fprog`
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::barcode::Symbology;
+
+    #[test]
+    fn test_msgs() {
+        let mut image = RgbImage::new(3, 2);
+        image.put_pixel(2, 1, image::Rgb([1, 2, 3]));
+        let msgs = vec![
+            WorkerMsg::Run {
+                user_id: "42".into(),
+                values: "key a\ninteger 1\n".into(),
+                code: "print(\"hi\")\n".into(),
+            },
+            WorkerMsg::Print(PrinterMsg::Text("two\nlines".into())),
+            WorkerMsg::Print(PrinterMsg::Image(image.clone())),
+            WorkerMsg::Print(PrinterMsg::Barcode(
+                Barcode::new(Symbology::Code128, "a b").unwrap(),
+            )),
+            WorkerMsg::Store(String::new()),
+            WorkerMsg::Done,
+        ];
+        let mut buf = Vec::new();
+        for msg in &msgs {
+            write_msg(&mut buf, msg).unwrap();
+        }
+
+        let mut r = &buf[..];
+        assert!(matches!(
+            read_msg(&mut r).unwrap(),
+            Some(WorkerMsg::Run { user_id, values, code })
+                if user_id == "42" && values == "key a\ninteger 1\n" && code == "print(\"hi\")\n"
+        ));
+        assert!(matches!(
+            read_msg(&mut r).unwrap(),
+            Some(WorkerMsg::Print(PrinterMsg::Text(t))) if t == "two\nlines"
+        ));
+        assert!(matches!(
+            read_msg(&mut r).unwrap(),
+            Some(WorkerMsg::Print(PrinterMsg::Image(i))) if i == image
+        ));
+        assert!(matches!(
+            read_msg(&mut r).unwrap(),
+            Some(WorkerMsg::Print(PrinterMsg::Barcode(b))) if b.data() == "a b"
+        ));
+        assert!(matches!(read_msg(&mut r).unwrap(), Some(WorkerMsg::Store(v)) if v.is_empty()));
+        assert!(matches!(read_msg(&mut r).unwrap(), Some(WorkerMsg::Done)));
+        assert!(read_msg(&mut r).unwrap().is_none());
+
+        // Garbage from a misbehaving worker is an error
+        assert!(read_msg(&mut &b"text 10\nshort"[..]).is_err());
+        assert!(read_msg(&mut &b"image 1 3\n5abc"[..]).is_err());
+        assert!(read_msg(&mut &b"launch\n"[..]).is_err());
+        assert!(read_msg(&mut &b"text 99999999999\n"[..]).is_err());
+        let long = format!(
+            "store {}\n{}",
+            MAX_MSG_SIZE + 1,
+            "a".repeat(MAX_MSG_SIZE + 1)
+        );
+        assert!(read_msg(&mut long.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+//! Values Lua scripts keep between runs. Each user has a file in the store directory holding
+//! a `key` line followed by a typed value line for each value, like the print journal. The Lua
+//! worker is sent the same text, and sends it back when a script changes anything.
+
+use anyhow::{bail, ensure, format_err, Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::queue::{escape, unescape};
+
+/// Something a script stored
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreValue {
+    String(String),
+    Integer(i64),
+    Number(f64),
+    Boolean(bool),
+}
+
+impl StoreValue {
+    /// Space counted against the user's quota
+    fn bytes(&self) -> usize {
+        match self {
+            StoreValue::String(s) => s.len(),
+            StoreValue::Integer(_) | StoreValue::Number(_) => 8,
+            StoreValue::Boolean(_) => 1,
+        }
+    }
+}
+
+/// One user's values, loaded for the length of a script
+#[derive(Debug)]
+pub struct UserValues {
+    values: BTreeMap<String, StoreValue>,
+    max_bytes: usize,
+    changed: bool,
+}
+
+impl UserValues {
+    /// Read values written by `to_text`
+    pub fn parse(text: &str, max_bytes: usize) -> Result<Self> {
+        let mut values = BTreeMap::new();
+        let mut key = None;
+        for line in text.lines() {
+            let (kind, value) = match line.find(' ') {
+                Some(idx) => (&line[..idx], &line[idx + 1..]),
+                None => (line, ""),
+            };
+            if kind == "key" {
+                key = Some(unescape(value));
+                continue;
+            }
+            let value = match kind {
+                "string" => StoreValue::String(unescape(value)),
+                "integer" => StoreValue::Integer(value.parse()?),
+                "number" => StoreValue::Number(value.parse()?),
+                "boolean" => StoreValue::Boolean(value.parse()?),
+                _ => bail!("Unknown line in stored values: {}", line),
+            };
+            let key = key
+                .take()
+                .ok_or_else(|| format_err!("Stored value has no key"))?;
+            values.insert(key, value);
+        }
+
+        Ok(Self {
+            values,
+            max_bytes,
+            changed: false,
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (key, value) in &self.values {
+            text.push_str(&format!("key {}\n", escape(key)));
+            text.push_str(&match value {
+                StoreValue::String(s) => format!("string {}\n", escape(s)),
+                StoreValue::Integer(i) => format!("integer {}\n", i),
+                StoreValue::Number(n) => format!("number {}\n", n),
+                StoreValue::Boolean(b) => format!("boolean {}\n", b),
+            });
+        }
+        text
+    }
+
+    pub fn get(&self, key: &str) -> Option<&StoreValue> {
+        self.values.get(key)
+    }
+
+    /// Store a value, or remove it with `None`. Fails if it would take the user over quota.
+    pub fn set(&mut self, key: &str, value: Option<StoreValue>) -> Result<()> {
+        let old = self.values.get(key).map_or(0, |v| key.len() + v.bytes());
+        let new = value.as_ref().map_or(0, |v| key.len() + v.bytes());
+        let used = self.bytes() - old + new;
+        ensure!(
+            new <= old || used <= self.max_bytes,
+            "Store full, that would use {} of {} bytes",
+            used,
+            self.max_bytes
+        );
+
+        match value {
+            Some(value) => self.values.insert(key.to_string(), value),
+            None => self.values.remove(key),
+        };
+        self.changed = true;
+        Ok(())
+    }
+
+    /// Space used, in bytes
+    pub fn bytes(&self) -> usize {
+        self.values.iter().map(|(k, v)| k.len() + v.bytes()).sum()
+    }
+
+    /// Whether anything was set since loading
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+}
+
+/// Directory of every user's values
+pub struct Store {
+    dir: PathBuf,
+    max_bytes: usize,
+}
+
+impl Store {
+    /// Open (creating if necessary) the store directory, allowing each user `max_bytes`
+    pub fn open(dir: impl AsRef<Path>, max_bytes: usize) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        Ok(Self { dir, max_bytes })
+    }
+
+    fn user_file(&self, user_id: &str) -> Result<PathBuf> {
+        ensure!(
+            !user_id.is_empty() && user_id.chars().all(|c| c.is_ascii_alphanumeric()),
+            "Invalid user id {:?}",
+            user_id
+        );
+        Ok(self.dir.join(format!("{}.txt", user_id)))
+    }
+
+    /// Values sent back by the Lua worker, which mustn't be trusted to have kept to the quota
+    pub fn parse(&self, text: &str) -> Result<UserValues> {
+        let values = UserValues::parse(text, self.max_bytes)?;
+        ensure!(
+            values.bytes() <= self.max_bytes,
+            "Stored values are over quota, at {} of {} bytes",
+            values.bytes(),
+            self.max_bytes
+        );
+        Ok(values)
+    }
+
+    /// A user's values, empty if they've never stored any
+    pub fn load(&self, user_id: &str) -> Result<UserValues> {
+        let path = self.user_file(user_id)?;
+        let text = match path.exists() {
+            true => fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read stored values of {}", user_id))?,
+            false => String::new(),
+        };
+        UserValues::parse(&text, self.max_bytes)
+            .with_context(|| format!("Stored values of {} are corrupt", user_id))
+    }
+
+    /// Write a user's values back
+    pub fn save(&self, user_id: &str, values: &UserValues) -> Result<()> {
+        let path = self.user_file(user_id)?;
+        if values.values.is_empty() {
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+            return Ok(());
+        }
+
+        // Replace the old file in one go, so a crash never loses everything
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, values.to_text())?;
+        fs::rename(&tmp, &path).with_context(|| format!("Failed to store values of {}", user_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store() {
+        let dir = std::env::temp_dir().join(format!("print_bot_store_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let store = Store::open(&dir, 64).unwrap();
+        let mut values = store.load("42").unwrap();
+        assert!(values.get("count").is_none());
+        assert!(!values.changed());
+
+        let name = "line one\nback\\slash";
+        values.set("count", Some(StoreValue::Integer(3))).unwrap();
+        values
+            .set("name key", Some(StoreValue::String(name.into())))
+            .unwrap();
+        values.set("pi", Some(StoreValue::Number(0.1))).unwrap();
+        assert_eq!(values.bytes(), 5 + 8 + 8 + name.len() + 2 + 8);
+        store.save("42", &values).unwrap();
+
+        // Over quota, but shrinking or removing values is always allowed
+        assert!(values
+            .set("big", Some(StoreValue::String("x".repeat(64))))
+            .is_err());
+        values.set("pi", Some(StoreValue::Boolean(true))).unwrap();
+        values.set("pi", None).unwrap();
+
+        let loaded = store.load("42").unwrap();
+        assert_eq!(loaded.get("count"), Some(&StoreValue::Integer(3)));
+        assert_eq!(
+            loaded.get("name key"),
+            Some(&StoreValue::String(name.into()))
+        );
+        assert_eq!(loaded.get("pi"), Some(&StoreValue::Number(0.1)));
+
+        // Values from the worker have to be within the quota
+        assert!(store.parse(&values.to_text()).is_ok());
+        let mut big = UserValues::parse("", 1000).unwrap();
+        big.set("big", Some(StoreValue::String("x".repeat(64))))
+            .unwrap();
+        assert!(store.parse(&big.to_text()).is_err());
+
+        // Users are kept apart, and can't escape the directory
+        assert!(store.load("43").unwrap().get("count").is_none());
+        assert!(store.load("../42").is_err());
+
+        // Emptying the store deletes the file
+        let mut values = store.load("42").unwrap();
+        for key in ["count", "name key", "pi"] {
+            values.set(key, None).unwrap();
+        }
+        store.save("42", &values).unwrap();
+        assert!(!dir.join("42.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+//! Text file attachments: plain text, Markdown, and source code, which is printed with line
+//! numbers.
+
+use anyhow::{bail, ensure, Result};
+
+use crate::layout::char_columns;
+
+/// Largest text file printed, in bytes
+pub const MAX_TEXT_SIZE: u64 = 32 * 1024;
+const TAB_WIDTH: usize = 4;
+
+/// Extensions of files printed as code
+const CODE_EXTENSIONS: [&str; 32] = [
+    "c", "cc", "cfg", "cpp", "cs", "css", "go", "h", "hpp", "hs", "html", "ini", "java", "js",
+    "json", "kt", "lua", "nix", "php", "py", "rb", "rs", "scss", "sh", "sql", "swift", "toml",
+    "ts", "xml", "yaml", "yml", "zig",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextKind {
+    Plain,
+    /// Printed with Discord style Markdown
+    Markdown,
+    /// Printed with line numbers
+    Code,
+}
+
+impl TextKind {
+    /// Recognise a text file by its extension
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        let extension = name[name.rfind('.')? + 1..].to_ascii_lowercase();
+        match extension.as_str() {
+            "txt" | "log" => Some(Self::Plain),
+            "md" | "markdown" => Some(Self::Markdown),
+            ext if CODE_EXTENSIONS.contains(&ext) => Some(Self::Code),
+            _ => None,
+        }
+    }
+}
+
+/// Check a downloaded file is short enough to print and actually text
+pub fn decode(data: &[u8]) -> Result<String> {
+    ensure!(
+        data.len() as u64 <= MAX_TEXT_SIZE,
+        "Text files can be at most {} KB",
+        MAX_TEXT_SIZE / 1024
+    );
+    let text = match std::str::from_utf8(data) {
+        Ok(text) if !text.contains('\0') => text,
+        _ => bail!("That file isn't UTF-8 text"),
+    };
+    Ok(text.trim_start_matches('\u{feff}').replace("\r\n", "\n"))
+}
+
+/// Number each line, wrapping long lines to `columns` so they stay clear of the numbers
+pub fn number_lines(text: &str, columns: usize) -> String {
+    let lines: Vec<String> = text.lines().map(expand_tabs).collect();
+    let gutter = lines.len().to_string().len();
+    let width = columns.saturating_sub(gutter + 1).max(1);
+
+    let mut out = String::with_capacity(text.len() * 2);
+    for (number, line) in lines.iter().enumerate() {
+        let line = line.trim_end();
+        out.push_str(&format!("{:>1$} ", number + 1, gutter));
+        let mut used = 0;
+        for c in line.chars() {
+            let w = char_columns(c);
+            if used + w > width {
+                out.push('\n');
+                out.push_str(&" ".repeat(gutter + 1));
+                used = 0;
+            }
+            out.push(c);
+            used += w;
+        }
+        out.push('\n');
+    }
+    out.pop();
+    out
+}
+
+/// Replace tabs with spaces up to the next tab stop
+fn expand_tabs(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut column = 0;
+    for c in line.chars() {
+        match c {
+            '\t' => {
+                let spaces = TAB_WIDTH - column % TAB_WIDTH;
+                out.push_str(&" ".repeat(spaces));
+                column += spaces;
+            }
+            c => {
+                out.push(c);
+                column += char_columns(c);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kinds() {
+        assert_eq!(TextKind::from_file_name("notes.TXT"), Some(TextKind::Plain));
+        assert_eq!(TextKind::from_file_name("README.md"), Some(TextKind::Markdown));
+        assert_eq!(TextKind::from_file_name("main.rs"), Some(TextKind::Code));
+        assert_eq!(TextKind::from_file_name("photo.png"), None);
+        assert_eq!(TextKind::from_file_name("rs"), None);
+
+        assert_eq!(decode(b"\xef\xbb\xbfa\r\nb").unwrap(), "a\nb");
+        assert!(decode(b"\xff\xfe").is_err());
+        assert!(decode(b"PK\x03\x04\0\0").is_err());
+        assert!(decode(&vec![b'a'; MAX_TEXT_SIZE as usize + 1]).is_err());
+    }
+
+    #[test]
+    fn test_number_lines() {
+        let code = "fn main() {\n\tprintln!(\"hi\");\n}\n";
+        assert_eq!(
+            number_lines(code, 32),
+            "1 fn main() {\n2     println!(\"hi\");\n3 }"
+        );
+
+        // Long lines wrap under the code, not the numbers
+        let code = format!("{}\n", "x".repeat(10)).repeat(10);
+        let numbered = number_lines(&code, 8);
+        let lines: Vec<&str> = numbered.lines().collect();
+        assert_eq!(lines.len(), 20);
+        assert_eq!(lines[0], " 1 xxxxx");
+        assert_eq!(lines[1], "   xxxxx");
+        assert_eq!(lines[18], "10 xxxxx");
+        assert!(lines.iter().all(|l| l.len() <= 8));
+    }
+}